@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::sleep;
+
+use serde::{Deserialize, Serialize};
+
+/// What a change did to its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+/// A committed change of a single key.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    /// The global sequence number, increasing by one on every change.
+    pub seq: u64,
+    pub key: String,
+    pub operation: Operation,
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    /// The name of the secret which made the change, if known.
+    pub author: Option<String>,
+    /// The new value, `None` if the key was deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// The json-patch applied, for `Operation::Patch`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<serde_json::Value>,
+}
+
+/// The current unix time in milliseconds.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Wait until the unix time in milliseconds, forever if `None`.
+pub async fn wait_until(time: Option<u64>) {
+    match time {
+        Some(time) => sleep(Duration::from_millis(time.saturating_sub(unix_millis()))).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A message published to an ephemeral channel. It is never stored nor replayed.
+#[derive(Debug, Clone)]
+pub struct Publication {
+    pub channel: String,
+    pub value: serde_json::Value,
+}
+
+/// What flows through the broadcaster.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Change(Change),
+    Publish(Publication),
+}
+
+/// A bounded buffer of the most recent changes. \
+/// Used to replay the changes that a reconnecting client has missed.
+pub struct ChangeLog {
+    capacity: usize,
+    changes: VecDeque<Change>,
+    latest_seq: u64,
+}
+
+impl ChangeLog {
+    /// `latest_seq` is the sequence number the server started at.
+    pub fn new(capacity: usize, latest_seq: u64) -> Self {
+        Self {
+            capacity,
+            changes: VecDeque::with_capacity(capacity),
+            latest_seq,
+        }
+    }
+
+    /// Push a change, dropping the oldest one if the buffer is full.
+    pub fn push(&mut self, change: Change) {
+        self.latest_seq = change.seq;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.changes.len() == self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// The sequence number of the latest change pushed.
+    pub fn latest_seq(&self) -> u64 {
+        self.latest_seq
+    }
+
+    /// The lowest `since` still covered by the buffer.
+    pub fn oldest_since(&self) -> u64 {
+        self.changes
+            .front()
+            .map_or(self.latest_seq, |oldest| oldest.seq - 1)
+    }
+
+    /// Get every change after `since`, in order. \
+    /// Returns `None` if the buffer no longer covers that point.
+    pub fn since(&self, since: u64) -> Option<Vec<Change>> {
+        if since > self.latest_seq {
+            // The client saw a sequence we don't know about, e.g. before a restart.
+            return None;
+        }
+        if since == self.latest_seq {
+            return Some(Vec::new());
+        }
+        match self.changes.front() {
+            Some(oldest) if oldest.seq <= since + 1 => Some(
+                self.changes
+                    .iter()
+                    .filter(|change| change.seq > since)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(seq: u64) -> Change {
+        Change {
+            seq,
+            key: "score".to_owned(),
+            operation: Operation::Put,
+            timestamp: 0,
+            author: None,
+            value: Some(serde_json::json!(seq)),
+            patch: None,
+        }
+    }
+
+    fn seqs(changes: Option<Vec<Change>>) -> Option<Vec<u64>> {
+        changes.map(|changes| changes.iter().map(|change| change.seq).collect())
+    }
+
+    fn change_log(
+        capacity: usize,
+        latest_seq: u64,
+        changes: impl IntoIterator<Item = u64>,
+    ) -> ChangeLog {
+        let mut log = ChangeLog::new(capacity, latest_seq);
+        for seq in changes {
+            log.push(change(seq));
+        }
+        log
+    }
+
+    #[test]
+    fn since_empty_log() {
+        let log = change_log(4, 0, []);
+        assert_eq!(seqs(log.since(0)), Some(vec![]));
+        assert_eq!(seqs(log.since(1)), None);
+    }
+
+    #[test]
+    fn since_within_buffer() {
+        let log = change_log(4, 0, 1..=3);
+        assert_eq!(seqs(log.since(0)), Some(vec![1, 2, 3]));
+        assert_eq!(seqs(log.since(1)), Some(vec![2, 3]));
+        assert_eq!(seqs(log.since(2)), Some(vec![3]));
+        assert_eq!(seqs(log.since(3)), Some(vec![]));
+    }
+
+    #[test]
+    fn since_ahead_of_log() {
+        // The client saw changes the server doesn't know about.
+        let log = change_log(4, 0, 1..=3);
+        assert_eq!(seqs(log.since(4)), None);
+        assert_eq!(seqs(log.since(u64::MAX)), None);
+    }
+
+    #[test]
+    fn since_evicted() {
+        let log = change_log(3, 0, 1..=5);
+        assert_eq!(log.latest_seq(), 5);
+        // The oldest buffered change is 3, so the client must have seen 2.
+        assert_eq!(seqs(log.since(0)), None);
+        assert_eq!(seqs(log.since(1)), None);
+        assert_eq!(seqs(log.since(2)), Some(vec![3, 4, 5]));
+        assert_eq!(seqs(log.since(4)), Some(vec![5]));
+        assert_eq!(seqs(log.since(5)), Some(vec![]));
+    }
+
+    #[test]
+    fn since_without_buffer() {
+        let log = change_log(0, 0, 1..=2);
+        assert_eq!(log.latest_seq(), 2);
+        assert_eq!(seqs(log.since(1)), None);
+        assert_eq!(seqs(log.since(2)), Some(vec![]));
+        assert_eq!(seqs(log.since(3)), None);
+    }
+
+    #[test]
+    fn since_after_restart() {
+        // Restarted at seq 10, the changes before it aren't buffered anymore.
+        let log = change_log(4, 10, 11..=12);
+        assert_eq!(seqs(log.since(10)), Some(vec![11, 12]));
+        assert_eq!(seqs(log.since(9)), None);
+        assert_eq!(seqs(log.since(0)), None);
+        assert_eq!(seqs(log.since(13)), None);
+        let restarted = change_log(4, 10, []);
+        assert_eq!(seqs(restarted.since(10)), Some(vec![]));
+        assert_eq!(seqs(restarted.since(9)), None);
+    }
+
+    #[test]
+    fn oldest_since() {
+        assert_eq!(change_log(4, 0, []).oldest_since(), 0);
+        assert_eq!(change_log(4, 0, 1..=3).oldest_since(), 0);
+        assert_eq!(change_log(3, 0, 1..=5).oldest_since(), 2);
+        assert_eq!(change_log(0, 0, 1..=2).oldest_since(), 2);
+        assert_eq!(change_log(4, 10, []).oldest_since(), 10);
+        // The oldest since is always covered.
+        let log = change_log(3, 10, 11..=15);
+        assert_eq!(seqs(log.since(log.oldest_since())), Some(vec![13, 14, 15]));
+    }
+}
@@ -0,0 +1,172 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP network like `10.0.0.0/24` or `fd00::/8`. A bare address only matches itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            // IPv6 networks like `::/0` also cover the IPv4-mapped addresses.
+            (IpAddr::V6(net), IpAddr::V4(ip)) => same_prefix(
+                u128::from(net),
+                u128::from(ip.to_ipv6_mapped()),
+                128,
+                self.prefix,
+            ),
+            (IpAddr::V4(_), IpAddr::V6(_)) => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of the `bits` bits of both addresses are equal.
+fn same_prefix(net: u128, ip: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    shift >= bits || net >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address `{s}`"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in `{s}`"))?,
+            None => max,
+        };
+        // An IPv4-mapped network like `::ffff:10.0.0.0/104` is the IPv4 network `10.0.0.0/8`.
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(Self {
+                addr: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Self { addr, prefix }),
+        }
+    }
+}
+
+/// Whether any of the networks contains the address.
+pub fn any_contains(networks: &[String], ip: IpAddr) -> bool {
+    networks
+        .iter()
+        .filter_map(|network| network.parse::<Cidr>().ok())
+        .any(|network| network.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!(
+            cidr("10.0.0.0/8"),
+            Cidr {
+                addr: ip("10.0.0.0"),
+                prefix: 8
+            }
+        );
+        assert_eq!(cidr(" 10.0.0.1 ").prefix, 32);
+        assert_eq!(cidr("fd00::/8").prefix, 8);
+        assert_eq!(cidr("::1").prefix, 128);
+        assert_eq!(cidr("0.0.0.0/0").prefix, 0);
+        assert_eq!(cidr("::/0").prefix, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("".parse::<Cidr>().is_err());
+        assert!("10.0.0".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/8/8".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        let network = cidr("10.1.0.0/16");
+        assert!(network.contains(ip("10.1.0.0")));
+        assert!(network.contains(ip("10.1.255.255")));
+        assert!(!network.contains(ip("10.2.0.0")));
+        assert!(!network.contains(ip("10.0.255.255")));
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+        // Host bits of the network are ignored.
+        assert!(cidr("10.1.2.3/16").contains(ip("10.1.9.9")));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        let network = cidr("fd00::/8");
+        assert!(network.contains(ip("fd12:3456::1")));
+        assert!(!network.contains(ip("fe80::1")));
+        assert!(cidr("::1").contains(ip("::1")));
+        assert!(!cidr("::1").contains(ip("::2")));
+        assert!(!network.contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("fd00::1")));
+    }
+
+    #[test]
+    fn zero_prefix_contains_everything() {
+        assert!(cidr("0.0.0.0/0").contains(ip("0.0.0.0")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("::")));
+        assert!(cidr("::/0").contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(cidr("::/0").contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6() {
+        // Peers of dual-stack sockets show up as IPv4-mapped IPv6 addresses.
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("::ffff:10.0.0.1"), cidr("10.0.0.1"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.9.9.9")));
+        assert!(cidr("::ffff:0:0/96").contains(ip("192.0.2.1")));
+        assert!(!cidr("::ffff:0:0/96").contains(ip("::1")));
+    }
+
+    #[test]
+    fn any_contains_skips_invalid_networks() {
+        let networks = ["nope".to_owned(), "10.0.0.0/8".to_owned()];
+        assert!(any_contains(&networks, ip("10.0.0.1")));
+        assert!(!any_contains(&networks, ip("192.168.0.1")));
+        assert!(!any_contains(&[], ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,89 @@
+use axum::extract::ws::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The encoding of websocket messages. JSON is sent as text frames, the others as binary frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[serde(alias = "messagepack")]
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Every subprotocol, in the order of preference.
+    pub const PROTOCOLS: [&'static str; 3] = ["jsonkv.msgpack", "jsonkv.cbor", "jsonkv"];
+
+    /// Get the encoding of the negotiated subprotocol.
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "jsonkv" => Some(Encoding::Json),
+            "jsonkv.msgpack" => Some(Encoding::Msgpack),
+            "jsonkv.cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Message, CodecError> {
+        match self {
+            Encoding::Json => Ok(Message::Text(serde_json::to_string(value)?)),
+            // Named, so structs are encoded as maps like in JSON instead of arrays.
+            Encoding::Msgpack => Ok(Message::Binary(rmp_serde::to_vec_named(value)?)),
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf)
+                    .map_err(|err| CodecError::Cbor(err.to_string()))?;
+                Ok(Message::Binary(buf))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(data)?),
+            Encoding::Msgpack => Ok(rmp_serde::from_slice(data)?),
+            Encoding::Cbor => {
+                ciborium::from_reader(data).map_err(|err| CodecError::Cbor(err.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    MsgpackEncode(rmp_serde::encode::Error),
+    MsgpackDecode(rmp_serde::decode::Error),
+    Cbor(String),
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Json(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CodecError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        CodecError::MsgpackEncode(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CodecError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        CodecError::MsgpackDecode(err)
+    }
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Json(err) => write!(f, "{}", err),
+            CodecError::MsgpackEncode(err) => write!(f, "{}", err),
+            CodecError::MsgpackDecode(err) => write!(f, "{}", err),
+            CodecError::Cbor(err) => write!(f, "{}", err),
+        }
+    }
+}
@@ -1,5 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::{env, fs};
+use sha2::{Digest, Sha256};
+use std::{env, fs, net::IpAddr};
+
+use crate::changes::unix_millis;
+use crate::cidr::{self, Cidr};
+use crate::tokens::{TOKEN_NAME_PREFIX, TOKEN_PREFIX};
+use subtle::ConstantTimeEq;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -13,6 +19,104 @@ pub struct Config {
     pub secret_file_path: String,
     /// Enable `GET /list` route.
     pub enable_list: bool,
+    /// The number of recent changes kept for resuming clients.
+    pub replay_buffer_size: usize,
+    /// The capacity of the broadcast channel. Slower websocket clients lag behind and get resynced.
+    pub broadcast_capacity: usize,
+    /// The time a websocket client has to authenticate before being closed. (in milliseconds)
+    pub ws_auth_timeout: u64,
+    /// The interval to ping websocket clients. (in milliseconds)
+    pub ws_ping_interval: u64,
+    /// The time without any frame from a websocket client before it is disconnected. (in milliseconds)
+    pub ws_ping_timeout: u64,
+    /// The maximum time a long-polling `GET /data/:key?wait=` is held open. (in milliseconds)
+    pub long_poll_max_wait: u64,
+    /// The longest `expires_in` of a signed token. (in seconds)
+    pub token_max_lifetime: u64,
+    /// Glob patterns of the keys anyone may read without a token.
+    pub public_keys: Vec<String>,
+    /// The proxies whose `X-Forwarded-For` header is trusted for the client address.
+    pub trusted_proxies: Vec<Cidr>,
+    /// The file the writes of every secret are appended to.
+    pub audit_log_path: String,
+    /// The size the audit log is rotated at. (in bytes)
+    pub audit_log_max_size: u64,
+    /// The number of rotated audit logs to keep.
+    pub audit_log_max_files: usize,
+    /// The CORS policy of the data API: `/data`, `/list`, `/events`, `/changes` and `/publish`.
+    pub cors_data: CorsPolicy,
+    /// The CORS policy of `/listen`.
+    pub cors_listen: CorsPolicy,
+    /// The CORS policy of `/admin`, `/clients` and `/metrics`.
+    pub cors_admin: CorsPolicy,
+    /// The PEM certificate chain. TLS is enabled if set, along with `tls_key_path`.
+    pub tls_cert_path: Option<String>,
+    /// The PEM private key of the certificate.
+    pub tls_key_path: Option<String>,
+    /// The PEM CA certificates client certificates are verified against.
+    /// Client certificates are only requested if set.
+    pub tls_client_ca_path: Option<String>,
+    /// Reject clients without a certificate.
+    pub tls_client_cert_required: bool,
+    /// The failed authentications allowed from an IP before it's locked out. `0` disables the lockout.
+    pub auth_max_failures: u32,
+    /// The first lockout, doubled on every further failure. (in milliseconds)
+    pub auth_lockout: u64,
+    /// The longest lockout. (in milliseconds)
+    pub auth_lockout_max: u64,
+    /// The failed `Authenticate` messages before a websocket is closed.
+    pub ws_max_auth_failures: u32,
+    /// The sustained HTTP writes and websocket messages per second of a secret. `0` disables the limit.
+    pub rate_limit_secret: f64,
+    /// The requests a secret may make at once, above `rate_limit_secret`.
+    pub rate_limit_secret_burst: f64,
+    /// The sustained HTTP writes and websocket messages per second of a remote IP. `0` disables the limit.
+    pub rate_limit_ip: f64,
+    /// The requests a remote IP may make at once, above `rate_limit_ip`.
+    pub rate_limit_ip_burst: f64,
+    /// The concurrent websocket connections of a secret. `0` is unlimited.
+    pub ws_max_connections_per_secret: usize,
+}
+
+/// Which cross-origin requests browsers may make. No origin is allowed by default.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// Allow every origin, method and header, ignoring the other fields.
+    pub permissive: bool,
+    /// The allowed origins, e.g. `https://example.com`. `*` allows any origin.
+    pub origins: Vec<String>,
+    /// `*` allows any method.
+    pub methods: Vec<String>,
+    /// The allowed request headers. `*` allows any header.
+    pub headers: Vec<String>,
+    /// Allow cookies and the `Authorization` header. Can't be used with `*`.
+    pub credentials: bool,
+    /// The time browsers may cache a preflight response. (in seconds)
+    pub max_age: Option<u64>,
+}
+
+impl CorsPolicy {
+    /// Whether a browser at `origin` may connect, e.g. to a websocket which isn't covered by CORS.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.permissive
+            || self
+                .origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            permissive: false,
+            origins: Vec::new(),
+            methods: split_list("GET,POST,PUT,PATCH,DELETE"),
+            headers: split_list("authorization,content-type,accept,last-event-id"),
+            credentials: false,
+            max_age: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -23,6 +127,34 @@ impl Default for Config {
             save_interval: 1000,
             secret_file_path: "./secret.toml".to_owned(),
             enable_list: true,
+            replay_buffer_size: 1024,
+            broadcast_capacity: 32,
+            ws_auth_timeout: 10000,
+            ws_ping_interval: 15000,
+            ws_ping_timeout: 45000,
+            long_poll_max_wait: 60000,
+            token_max_lifetime: 30 * 24 * 60 * 60,
+            public_keys: Vec::new(),
+            trusted_proxies: Vec::new(),
+            audit_log_path: "./audit.log".to_owned(),
+            audit_log_max_size: 10 * 1024 * 1024,
+            audit_log_max_files: 5,
+            cors_data: CorsPolicy::default(),
+            cors_listen: CorsPolicy::default(),
+            cors_admin: CorsPolicy::default(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            tls_client_cert_required: false,
+            auth_max_failures: 5,
+            auth_lockout: 1000,
+            auth_lockout_max: 300000,
+            ws_max_auth_failures: 1,
+            rate_limit_secret: 100.0,
+            rate_limit_secret_burst: 200.0,
+            rate_limit_ip: 200.0,
+            rate_limit_ip_burst: 400.0,
+            ws_max_connections_per_secret: 100,
         }
     }
 }
@@ -42,14 +174,356 @@ pub fn parse_from_env() -> Config {
     if let Ok(enable_list) = env::var("JSONKV_ENABLE_LIST") {
         config.enable_list = enable_list.parse().unwrap();
     }
+    if let Ok(replay_buffer_size) = env::var("JSONKV_REPLAY_BUFFER") {
+        config.replay_buffer_size = replay_buffer_size.parse().unwrap();
+    }
+    if let Ok(broadcast_capacity) = env::var("JSONKV_BROADCAST_CAPACITY") {
+        config.broadcast_capacity = broadcast_capacity.parse().unwrap();
+    }
+    if let Ok(ws_auth_timeout) = env::var("JSONKV_WS_AUTH_TIMEOUT") {
+        config.ws_auth_timeout = ws_auth_timeout.parse().unwrap();
+    }
+    if let Ok(ws_ping_interval) = env::var("JSONKV_WS_PING_INTERVAL") {
+        config.ws_ping_interval = ws_ping_interval.parse().unwrap();
+    }
+    if let Ok(ws_ping_timeout) = env::var("JSONKV_WS_PING_TIMEOUT") {
+        config.ws_ping_timeout = ws_ping_timeout.parse().unwrap();
+    }
+    if let Ok(long_poll_max_wait) = env::var("JSONKV_LONG_POLL_MAX_WAIT") {
+        config.long_poll_max_wait = long_poll_max_wait.parse().unwrap();
+    }
+    if let Ok(token_max_lifetime) = env::var("JSONKV_TOKEN_MAX_LIFETIME") {
+        config.token_max_lifetime = token_max_lifetime.parse().unwrap();
+    }
+    if let Ok(public_keys) = env::var("JSONKV_PUBLIC_KEYS") {
+        config.public_keys = split_list(&public_keys);
+    }
+    if let Ok(trusted_proxies) = env::var("JSONKV_TRUSTED_PROXIES") {
+        config.trusted_proxies = split_list(&trusted_proxies)
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .unwrap_or_else(|err| panic!("JSONKV_TRUSTED_PROXIES: {err}"))
+            })
+            .collect();
+    }
+    if let Ok(audit_log_path) = env::var("JSONKV_AUDIT_LOG") {
+        config.audit_log_path = audit_log_path;
+    }
+    if let Ok(audit_log_max_size) = env::var("JSONKV_AUDIT_LOG_MAX_SIZE") {
+        config.audit_log_max_size = audit_log_max_size.parse().unwrap();
+    }
+    if let Ok(audit_log_max_files) = env::var("JSONKV_AUDIT_LOG_MAX_FILES") {
+        config.audit_log_max_files = audit_log_max_files.parse().unwrap();
+    }
+    config.cors_data = parse_cors_from_env("DATA");
+    config.cors_listen = parse_cors_from_env("LISTEN");
+    config.cors_admin = parse_cors_from_env("ADMIN");
+    if let Ok(tls_cert_path) = env::var("JSONKV_TLS_CERT") {
+        config.tls_cert_path = Some(tls_cert_path);
+    }
+    if let Ok(tls_key_path) = env::var("JSONKV_TLS_KEY") {
+        config.tls_key_path = Some(tls_key_path);
+    }
+    if let Ok(tls_client_ca_path) = env::var("JSONKV_TLS_CLIENT_CA") {
+        config.tls_client_ca_path = Some(tls_client_ca_path);
+    }
+    if let Ok(tls_client_cert_required) = env::var("JSONKV_TLS_CLIENT_CERT_REQUIRED") {
+        config.tls_client_cert_required = tls_client_cert_required.parse().unwrap();
+    }
+    if let Ok(auth_max_failures) = env::var("JSONKV_AUTH_MAX_FAILURES") {
+        config.auth_max_failures = auth_max_failures.parse().unwrap();
+    }
+    if let Ok(auth_lockout) = env::var("JSONKV_AUTH_LOCKOUT") {
+        config.auth_lockout = auth_lockout.parse().unwrap();
+    }
+    if let Ok(auth_lockout_max) = env::var("JSONKV_AUTH_LOCKOUT_MAX") {
+        config.auth_lockout_max = auth_lockout_max.parse().unwrap();
+    }
+    if let Ok(ws_max_auth_failures) = env::var("JSONKV_WS_MAX_AUTH_FAILURES") {
+        config.ws_max_auth_failures = ws_max_auth_failures.parse().unwrap();
+    }
+    if let Ok(rate_limit_secret) = env::var("JSONKV_RATE_LIMIT_SECRET") {
+        config.rate_limit_secret = rate_limit_secret.parse().unwrap();
+    }
+    if let Ok(rate_limit_secret_burst) = env::var("JSONKV_RATE_LIMIT_SECRET_BURST") {
+        config.rate_limit_secret_burst = rate_limit_secret_burst.parse().unwrap();
+    }
+    if let Ok(rate_limit_ip) = env::var("JSONKV_RATE_LIMIT_IP") {
+        config.rate_limit_ip = rate_limit_ip.parse().unwrap();
+    }
+    if let Ok(rate_limit_ip_burst) = env::var("JSONKV_RATE_LIMIT_IP_BURST") {
+        config.rate_limit_ip_burst = rate_limit_ip_burst.parse().unwrap();
+    }
+    if let Ok(ws_max_connections_per_secret) = env::var("JSONKV_WS_MAX_CONNECTIONS_PER_SECRET") {
+        config.ws_max_connections_per_secret = ws_max_connections_per_secret.parse().unwrap();
+    }
     config
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Parse the CORS policy of a route group, e.g. `JSONKV_CORS_DATA_ORIGINS`.
+/// Unset settings fall back to the ones of every group, e.g. `JSONKV_CORS_ORIGINS`.
+fn parse_cors_from_env(group: &str) -> CorsPolicy {
+    let var = |name: &str| {
+        env::var(format!("JSONKV_CORS_{group}_{name}"))
+            .or_else(|_| env::var(format!("JSONKV_CORS_{name}")))
+    };
+    let mut policy = CorsPolicy::default();
+    if let Ok(permissive) = var("PERMISSIVE") {
+        policy.permissive = permissive.parse().unwrap();
+    }
+    if let Ok(origins) = var("ORIGINS") {
+        policy.origins = split_list(&origins);
+    }
+    if let Ok(methods) = var("METHODS") {
+        policy.methods = split_list(&methods);
+    }
+    if let Ok(headers) = var("HEADERS") {
+        policy.headers = split_list(&headers);
+    }
+    if let Ok(credentials) = var("CREDENTIALS") {
+        policy.credentials = credentials.parse().unwrap();
+    }
+    if let Ok(max_age) = var("MAX_AGE") {
+        policy.max_age = Some(max_age.parse().unwrap());
+    }
+    policy
+}
+
+/// Split a comma separated list, ignoring the empty items.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+impl Config {
+    /// The secret of unauthenticated clients, which may only read the public keys.
+    /// `None` if there are no public keys.
+    pub fn anonymous_secret(&self) -> Option<Secret> {
+        if self.public_keys.is_empty() {
+            return None;
+        }
+        Some(Secret {
+            secret: String::new(),
+            name: "anonymous".to_owned(),
+            description: None,
+            permissions: vec![Permission::Read],
+            keys: self.public_keys.clone(),
+            expires_at: None,
+            client_subject: None,
+            allowed_ips: Vec::new(),
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Secret {
+    /// The token, or its salted hash as `sha256$<salt>$<hash>`.
     pub secret: String,
     pub name: String,
     pub description: Option<String>,
+    /// What the secret may do. Every permission if omitted.
+    #[serde(default = "Permission::all")]
+    pub permissions: Vec<Permission>,
+    /// Glob patterns of the keys and channels the secret may access, e.g. `score*`.
+    /// Every key if omitted.
+    #[serde(default = "default_scopes")]
+    pub keys: Vec<String>,
+    /// Unix time in milliseconds after which the secret is no longer accepted.
+    pub expires_at: Option<u64>,
+    /// The subject of a client certificate authenticating as this secret, e.g. `CN=overlay, O=Venue`.
+    pub client_subject: Option<String>,
+    /// The networks the secret may be used from, e.g. `10.0.0.0/24`. Anywhere if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_ips: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Get, list and listen to keys, and subscribe to channels.
+    Read,
+    /// Create, update and patch keys, and publish to channels.
+    Write,
+    Delete,
+    /// Metrics, sessions, presence and secrets.
+    Admin,
+}
+
+impl Permission {
+    pub fn all() -> Vec<Permission> {
+        vec![
+            Permission::Read,
+            Permission::Write,
+            Permission::Delete,
+            Permission::Admin,
+        ]
+    }
+}
+
+pub fn default_scopes() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secret")
+            .field("secret", &"[redacted]")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("permissions", &self.permissions)
+            .field("keys", &self.keys)
+            .field("expires_at", &self.expires_at)
+            .field("client_subject", &self.client_subject)
+            .field("allowed_ips", &self.allowed_ips)
+            .finish()
+    }
+}
+
+/// The view of a `Secret` without the token.
+#[derive(Serialize, Debug, Clone)]
+pub struct SecretInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<Permission>,
+    pub keys: Vec<String>,
+    pub expires_at: Option<u64>,
+    pub client_subject: Option<String>,
+    pub allowed_ips: Vec<String>,
+    /// Whether only the hash of the token is stored.
+    pub hashed: bool,
+}
+
+/// The prefix of a hashed secret.
+const HASH_PREFIX: &str = "sha256$";
+
+impl Secret {
+    pub fn info(&self) -> SecretInfo {
+        SecretInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            permissions: self.permissions.clone(),
+            keys: self.keys.clone(),
+            expires_at: self.expires_at,
+            client_subject: self.client_subject.clone(),
+            allowed_ips: self.allowed_ips.clone(),
+            hashed: self.secret.starts_with(HASH_PREFIX),
+        }
+    }
+
+    /// Whether this is the secret of unauthenticated clients, see `Config::anonymous_secret`.
+    pub fn is_anonymous(&self) -> bool {
+        self.secret.is_empty()
+    }
+
+    /// Whether this is a signed token rather than a secret of the file.
+    pub fn is_signed(&self) -> bool {
+        self.secret.starts_with(TOKEN_PREFIX)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_millis())
+    }
+
+    /// Whether the token is this secret, compared in constant time.
+    pub fn matches(&self, token: &str) -> bool {
+        match self.secret.strip_prefix(HASH_PREFIX) {
+            Some(hashed) => match hashed.split_once('$') {
+                Some((salt, hash)) => constant_time_eq(&hash_with_salt(salt, token), hash),
+                None => false,
+            },
+            None => constant_time_eq(&self.secret, token),
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Whether the key matches any of the scopes.
+    pub fn in_scope(&self, key: &str) -> bool {
+        self.keys.iter().any(|pattern| glob_match(pattern, key))
+    }
+
+    /// Whether the secret has the permission on the key.
+    pub fn allows(&self, permission: Permission, key: &str) -> bool {
+        self.has_permission(permission) && self.in_scope(key)
+    }
+
+    /// Whether the secret may be used from the address.
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.is_empty() || cidr::any_contains(&self.allowed_ips, ip)
+    }
+
+    /// The secret, if it may be used from the address.
+    pub fn allowed_from(self, ip: IpAddr) -> Option<Self> {
+        if self.allows_ip(ip) {
+            return Some(self);
+        }
+        println!(
+            "Secret `{}` refused from {ip}, outside its allowed addresses.",
+            self.name
+        );
+        None
+    }
+}
+
+/// Hash the token with a random salt, as stored in `Secret::secret`.
+pub fn hash_secret(token: &str) -> String {
+    let salt = hex(&rand::random::<[u8; 16]>());
+    format!("{HASH_PREFIX}{salt}${}", hash_with_salt(&salt, token))
+}
+
+/// Generate a random token.
+pub fn generate_token() -> String {
+    hex(&rand::random::<[u8; 32]>())
+}
+
+fn hash_with_salt(salt: &str, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(token.as_bytes());
+    hex(&hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Match the text against a glob pattern, where `*` matches any sequence and `?` any character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*`, and the text position it was tried at.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let the last `*` match one more character.
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 /// The wrapper of `Secret`. \
@@ -60,37 +534,134 @@ pub struct Secrets {
 }
 
 impl Secrets {
-    pub fn contains_key(&self, key: &str) -> bool {
-        self.secret.iter().any(|s| s.secret == key)
+    /// Find the secret matching the given key.
+    pub fn get(&self, key: &str) -> Option<&Secret> {
+        self.secret
+            .iter()
+            .find(|s| !s.is_expired() && s.matches(key))
+    }
+
+    /// Find the secret mapped to the subject of a client certificate.
+    pub fn by_client_subject(&self, subject: &str) -> Option<&Secret> {
+        self.secret
+            .iter()
+            .find(|s| !s.is_expired() && s.client_subject.as_deref() == Some(subject))
+    }
+
+    /// Find the current version of a secret, which may have been reloaded since, unless it expired.
+    /// Signed tokens aren't in the file, so they're returned as is.
+    pub fn current<'a>(&'a self, secret: &'a Secret) -> Option<&'a Secret> {
+        let current = if secret.is_signed() {
+            Some(secret)
+        } else {
+            self.secret.iter().find(|s| s.secret == secret.secret)
+        };
+        current.filter(|s| !s.is_expired())
+    }
+
+    /// Check that every secret is usable.
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, secret) in self.secret.iter().enumerate() {
+            if secret.secret.is_empty() {
+                return Err(format!("secret `{}` is empty", secret.name));
+            }
+            if secret.is_signed() {
+                return Err(format!(
+                    "secret `{}` starts with `{TOKEN_PREFIX}`, which is reserved for signed tokens",
+                    secret.name
+                ));
+            }
+            if secret.name.starts_with(TOKEN_NAME_PREFIX) {
+                return Err(format!(
+                    "secret name `{}` starts with `{TOKEN_NAME_PREFIX}`, which is reserved for signed tokens",
+                    secret.name
+                ));
+            }
+            if let Some(hashed) = secret.secret.strip_prefix(HASH_PREFIX) {
+                if !hashed.contains('$') {
+                    return Err(format!("secret `{}` has a malformed hash", secret.name));
+                }
+            }
+            if self.secret[..index]
+                .iter()
+                .any(|s| s.secret == secret.secret)
+            {
+                return Err(format!("secret `{}` is duplicated", secret.name));
+            }
+            for network in &secret.allowed_ips {
+                if let Err(err) = network.parse::<Cidr>() {
+                    return Err(format!("secret `{}` has {err}", secret.name));
+                }
+            }
+            if secret.client_subject.is_some()
+                && self.secret[..index]
+                    .iter()
+                    .any(|s| s.client_subject == secret.client_subject)
+            {
+                return Err(format!(
+                    "secret `{}` has a duplicated client subject",
+                    secret.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read and validate the secrets from the given path.
+pub fn read_secrets(path: &str) -> Result<Secrets, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let secrets: Secrets = toml::from_str(&content).map_err(|err| err.to_string())?;
+    secrets.validate()?;
+    Ok(secrets)
+}
+
+/// Get the key signing the short-lived tokens from `JSONKV_TOKEN_KEY`. \
+/// Without it, a random key is used, so the tokens don't outlive the process.
+pub fn load_token_key() -> Vec<u8> {
+    match env::var("JSONKV_TOKEN_KEY") {
+        Ok(key) if !key.is_empty() => key.into_bytes(),
+        _ => rand::random::<[u8; 32]>().to_vec(),
     }
 }
 
+/// Save the secrets to the given path. \
+/// They are written to a temporary file first, then renamed over the old file,
+/// so it is never left half written.
+pub fn save_secrets(path: &str, secrets: &Secrets) -> std::io::Result<()> {
+    let serialized = toml::to_string(secrets)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    let temp_path = format!("{path}.tmp");
+    fs::write(&temp_path, serialized)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Only the owner may read the tokens.
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(&temp_path, path)
+}
+
 /// Load the secrets from the given path.
-/// If the file does not exist, create a sample and save it to the given path
+/// If the file does not exist, create it with a random admin token.
 pub fn load_secrets(path: &str) -> Secrets {
-    let path = std::path::Path::new(path);
-    if path.exists() {
-        let content = std::fs::read_to_string(path).unwrap();
-        let secrets: Secrets = toml::from_str(&content).unwrap();
-        secrets
+    if std::path::Path::new(path).exists() {
+        read_secrets(path).unwrap()
     } else {
-        let secret_sample = Secret {
-            secret: "secret".to_owned(),
-            name: "name".to_owned(),
-            description: None,
-        };
-        let another_secret = Secret {
-            secret: "another secret".to_owned(),
-            name: "another name".to_owned(),
-            ..secret_sample.clone()
-        };
-
         let secrets = Secrets {
-            secret: vec![secret_sample, another_secret],
+            secret: vec![Secret {
+                secret: generate_token(),
+                name: "admin".to_owned(),
+                description: Some("Generated on the first run.".to_owned()),
+                permissions: Permission::all(),
+                keys: default_scopes(),
+                expires_at: None,
+                client_subject: None,
+                allowed_ips: Vec::new(),
+            }],
         };
-
-        let serialized = toml::to_string(&secrets).unwrap();
-        fs::write(path, serialized).unwrap();
+        save_secrets(path, &secrets).unwrap();
+        println!("Created {path} with a random admin token.");
         secrets
     }
 }
@@ -111,3 +682,44 @@ pub fn parse_listen(listen: &str) -> ListenType {
         ListenType::Http(listen.to_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_literals() {
+        assert!(glob_match("score", "score"));
+        assert!(!glob_match("score", "scores"));
+        assert!(!glob_match("score", "scor"));
+        assert!(!glob_match("score", ""));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "score"));
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("score*", "score"));
+        assert!(glob_match("score*", "scoreHome"));
+        assert!(!glob_match("score*", "clock"));
+        assert!(glob_match("*Home", "scoreHome"));
+        assert!(!glob_match("*Home", "scoreAway"));
+        assert!(glob_match("s?ore", "score"));
+        assert!(!glob_match("s?ore", "sore"));
+        assert!(glob_match("a*b*c", "aXbYc"));
+        assert!(glob_match("a**c", "abc"));
+        assert!(!glob_match("a*b*c", "aXcYb"));
+    }
+
+    #[test]
+    fn glob_match_backtracks() {
+        // The first `b` doesn't lead to a match, the `*` must extend past it.
+        assert!(glob_match("*bc", "abbc"));
+        assert!(glob_match("a*bc", "abcbc"));
+        assert!(!glob_match("a*bc", "abcb"));
+        assert!(glob_match("*?", "a"));
+        assert!(!glob_match("*?", ""));
+    }
+}
@@ -1,18 +1,30 @@
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, watch, RwLock};
 
 use crate::{
+    changes::{ChangeLog, Event},
     config::{Config, Secrets},
+    lockout::Lockouts,
+    metrics::Metrics,
+    ratelimit::RateLimits,
     service::KeyService,
+    sessions::Sessions,
+    tokens::TokenSigner,
 };
 
 pub struct AppContext {
     pub config: Config,
     pub secrets: Arc<RwLock<Secrets>>,
+    /// Bumped whenever secrets are revoked or changed, so the open streams check theirs again.
+    pub secrets_reloaded: watch::Sender<u64>,
+    pub token_signer: TokenSigner,
 
-    pub hashmap: Arc<RwLock<std::collections::HashMap<String, serde_json::Value>>>,
-    pub sender_file_save: mpsc::Sender<(String, serde_json::Value)>,
-    pub broadcast: broadcast::Sender<(String, serde_json::Value)>,
+    pub broadcast: broadcast::Sender<Event>,
+    pub changes: Arc<RwLock<ChangeLog>>,
 
     pub key_service: Arc<KeyService>,
+    pub metrics: Arc<Metrics>,
+    pub sessions: Arc<Sessions>,
+    pub lockouts: Lockouts,
+    pub rate_limits: RateLimits,
 }
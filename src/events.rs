@@ -0,0 +1,303 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use futures::Stream;
+use serde_json::json;
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    watch,
+};
+
+use crate::{
+    changes::{wait_until, Change, Event},
+    config::{Permission, Secret},
+    context::AppContext,
+    service::KeyServiceTrait,
+};
+
+/// A Server-Sent Events stream of the changes of some keys.
+///
+/// The current values are sent first as `subscribed` events, unless `last_event_id` is given
+/// and the change log still covers it, in which case only the missed changes are replayed,
+/// followed by `resumed`. Every event carries the `seq` as its id, so a reconnecting
+/// `EventSource` resumes where it left off.
+pub async fn stream_events(
+    context: Arc<AppContext>,
+    secret: Secret,
+    keys: Vec<String>,
+    last_event_id: Option<u64>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    // Subscribe before reading the current state, so no change falls in between.
+    let receiver = context.broadcast.subscribe();
+    let secrets_reloaded = context.secrets_reloaded.subscribe();
+    let mut stream = EventStream {
+        context,
+        secret,
+        secrets_reloaded,
+        receiver,
+        listening: keys.into_iter().map(|key| (key, 0)).collect(),
+        pending: VecDeque::new(),
+    };
+    if !stream.resume(last_event_id).await {
+        stream.snapshot().await;
+    }
+
+    let stream = futures::stream::unfold(stream, |mut stream| async move {
+        stream.next().await.map(|event| (Ok(event), stream))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Stream every change after `since`, or every buffered one without it, as NDJSON,
+/// one change per line, followed by the live ones.
+/// Only the changes of the keys in the scopes of the secret are sent.
+/// Returns `None` if the change log no longer covers `since`.
+pub async fn stream_changes(
+    context: Arc<AppContext>,
+    secret: Secret,
+    since: Option<u64>,
+) -> Option<impl Stream<Item = Result<String, Infallible>>> {
+    // Subscribe before reading the change log, so no change falls in between.
+    let receiver = context.broadcast.subscribe();
+    let (since, backlog) = {
+        let log = context.changes.read().await;
+        let since = since.unwrap_or_else(|| log.oldest_since());
+        (since, log.since(since)?)
+    };
+    let secrets_reloaded = context.secrets_reloaded.subscribe();
+    let stream = ChangeStream {
+        context,
+        secret,
+        secrets_reloaded,
+        receiver,
+        last_seq: since,
+        pending: backlog.into(),
+    };
+    Some(futures::stream::unfold(stream, |mut stream| async move {
+        let change = stream.next().await?;
+        let line = serde_json::to_string(&change).unwrap() + "\n";
+        Some((Ok(line), stream))
+    }))
+}
+
+/// The current version of the secret of a stream after a reload, `None` if it was revoked.
+async fn reloaded_secret(context: &AppContext, secret: &Secret) -> Option<Secret> {
+    // The secret of the public keys isn't in the file.
+    if secret.is_anonymous() {
+        return Some(secret.clone());
+    }
+    context.secrets.read().await.current(secret).cloned()
+}
+
+struct ChangeStream {
+    context: Arc<AppContext>,
+    secret: Secret,
+    secrets_reloaded: watch::Receiver<u64>,
+    receiver: Receiver<Event>,
+    /// The `seq` of the last change sent.
+    last_seq: u64,
+    pending: VecDeque<Change>,
+}
+
+impl ChangeStream {
+    /// Wait for the next change. Returns `None` once the broadcaster is closed,
+    /// if the client fell behind further than the change log, or once the secret expires or is revoked.
+    async fn next(&mut self) -> Option<Change> {
+        loop {
+            while let Some(change) = self.pending.pop_front() {
+                if change.seq > self.last_seq {
+                    self.last_seq = change.seq;
+                    if self.secret.in_scope(&change.key) {
+                        return Some(change);
+                    }
+                }
+            }
+            let event = tokio::select! {
+                event = self.receiver.recv() => event,
+                _ = wait_until(self.secret.expires_at) => {
+                    println!("Change feed of `{}` closed, secret expired", self.secret.name);
+                    return None;
+                }
+                _ = self.secrets_reloaded.changed() => {
+                    // Narrowed scopes take effect on the next change, a revoked secret ends the feed.
+                    match reloaded_secret(&self.context, &self.secret).await {
+                        Some(secret) if secret.has_permission(Permission::Read) => self.secret = secret,
+                        _ => {
+                            println!("Change feed of `{}` closed, secret revoked", self.secret.name);
+                            return None;
+                        }
+                    }
+                    continue;
+                }
+            };
+            match event {
+                Ok(Event::Change(change)) => self.pending.push_back(change),
+                Ok(Event::Publish(_)) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    // Every change reaches the change log before the channel, so catch up from there.
+                    self.context
+                        .metrics
+                        .broadcast_lagged
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.context
+                        .metrics
+                        .broadcast_lagged_changes
+                        .fetch_add(skipped, Ordering::Relaxed);
+                    let missed = self.context.changes.read().await.since(self.last_seq);
+                    match missed {
+                        Some(missed) => self.pending.extend(missed),
+                        None => {
+                            println!("Change feed client lagged behind the change log, closing");
+                            return None;
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+struct EventStream {
+    context: Arc<AppContext>,
+    secret: Secret,
+    secrets_reloaded: watch::Receiver<u64>,
+    receiver: Receiver<Event>,
+    /// The keys and the `seq` of the last change sent for each.
+    listening: HashMap<String, u64>,
+    pending: VecDeque<SseEvent>,
+}
+
+impl EventStream {
+    /// Queue the changes after `since` from the change log.
+    /// Returns `false` if the log no longer covers it.
+    async fn resume(&mut self, since: Option<u64>) -> bool {
+        let Some(since) = since else {
+            return false;
+        };
+        let (missed, latest_seq) = {
+            let changes = self.context.changes.read().await;
+            match changes.since(since) {
+                Some(missed) => (missed, changes.latest_seq()),
+                None => return false,
+            }
+        };
+        for last_seq in self.listening.values_mut() {
+            *last_seq = since;
+        }
+        for change in missed {
+            self.push_change(change);
+        }
+        for last_seq in self.listening.values_mut() {
+            *last_seq = (*last_seq).max(latest_seq);
+        }
+        self.pending.push_back(
+            SseEvent::default()
+                .event("resumed")
+                .id(latest_seq.to_string())
+                .json_data(json!({ "seq": latest_seq }))
+                .unwrap(),
+        );
+        true
+    }
+
+    /// Queue the current value of every key.
+    async fn snapshot(&mut self) {
+        // Every change up to this point is reflected in the values read below,
+        // so resuming from it may repeat a change but never misses one.
+        let seq = self.context.key_service.seq.load(Ordering::SeqCst);
+        let mut keys: Vec<String> = self.listening.keys().cloned().collect();
+        keys.sort();
+        for key in keys {
+            let Ok(entry) = self.context.key_service.get_entry(&key).await else {
+                continue;
+            };
+            self.listening.insert(key.clone(), entry.revision);
+            self.pending.push_back(
+                SseEvent::default()
+                    .event("subscribed")
+                    .id(seq.to_string())
+                    .json_data(json!({ "key": key, "value": entry.value, "seq": entry.revision }))
+                    .unwrap(),
+            );
+        }
+    }
+
+    /// Queue the change if it's of a listened key and wasn't sent yet.
+    fn push_change(&mut self, change: Change) {
+        let Some(last_seq) = self.listening.get_mut(&change.key) else {
+            return;
+        };
+        if change.seq <= *last_seq {
+            return;
+        }
+        *last_seq = change.seq;
+        let event = SseEvent::default().id(change.seq.to_string());
+        let event = match change.value {
+            Some(value) => event
+                .event("data")
+                .json_data(json!({ "key": change.key, "value": value, "seq": change.seq })),
+            None => event
+                .event("deleted")
+                .json_data(json!({ "key": change.key, "seq": change.seq })),
+        };
+        self.pending.push_back(event.unwrap());
+    }
+
+    /// Wait for the next event to send. Returns `None` once the broadcaster is closed,
+    /// or once the secret expires, is revoked, or no longer allows the keys.
+    async fn next(&mut self) -> Option<SseEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let event = tokio::select! {
+                event = self.receiver.recv() => event,
+                _ = wait_until(self.secret.expires_at) => {
+                    println!("SSE stream of `{}` closed, secret expired", self.secret.name);
+                    return None;
+                }
+                _ = self.secrets_reloaded.changed() => {
+                    let allowed = reloaded_secret(&self.context, &self.secret).await.filter(|secret| {
+                        self.listening.keys().all(|key| secret.allows(Permission::Read, key))
+                    });
+                    match allowed {
+                        Some(secret) => self.secret = secret,
+                        None => {
+                            println!("SSE stream of `{}` closed, secret revoked or permissions changed", self.secret.name);
+                            return None;
+                        }
+                    }
+                    continue;
+                }
+            };
+            match event {
+                Ok(Event::Change(change)) => self.push_change(change),
+                Ok(Event::Publish(_)) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    // Missed changes can't be recovered from the channel, resend the current state instead.
+                    println!("SSE client lagged behind by {skipped} changes");
+                    self.context
+                        .metrics
+                        .broadcast_lagged
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.context
+                        .metrics
+                        .broadcast_lagged_changes
+                        .fetch_add(skipped, Ordering::Relaxed);
+                    self.pending.push_back(
+                        SseEvent::default()
+                            .event("lagged")
+                            .json_data(json!({ "skipped": skipped }))
+                            .unwrap(),
+                    );
+                    self.snapshot().await;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// The failed authentication attempts of a remote IP.
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Locks out the remote IPs failing to authenticate too often, for exponentially longer each time.
+/// Successful authentications don't reset the failures, or any valid token would let an IP guess others forever.
+pub struct Lockouts {
+    /// The failures allowed before locking out. `0` disables the lockout.
+    max_failures: u32,
+    /// The first lockout, doubled on each further failure.
+    base: Duration,
+    /// The longest lockout. An IP without failures for this long is forgotten.
+    max: Duration,
+    attempts: Mutex<HashMap<IpAddr, Attempts>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Lockouts {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            max_failures: config.auth_max_failures,
+            base: Duration::from_millis(config.auth_lockout),
+            max: Duration::from_millis(config.auth_lockout_max),
+            attempts: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// The remaining lockout of the IP, if it's locked out.
+    pub fn check(&self, ip: IpAddr) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let locked_until = attempts.get(&ip)?.locked_until?;
+        let remaining = locked_until.checked_duration_since(Instant::now())?;
+        self.metrics.auth_rejected.fetch_add(1, Ordering::Relaxed);
+        Some(remaining)
+    }
+
+    /// Record a failed attempt of the IP. Returns the lockout if it's now locked out.
+    pub fn record_failure(&self, ip: IpAddr) -> Option<Duration> {
+        self.metrics.auth_failures.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, attempts| now.duration_since(attempts.last_failure) < self.max);
+        let attempts = attempts.entry(ip).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        attempts.failures += 1;
+        attempts.last_failure = now;
+        println!(
+            "Failed authentication from {ip} ({} recent)",
+            attempts.failures
+        );
+        if self.max_failures == 0 || attempts.failures < self.max_failures {
+            return None;
+        }
+
+        let doublings = (attempts.failures - self.max_failures).min(31);
+        let lockout = self.base.saturating_mul(1 << doublings).min(self.max);
+        attempts.locked_until = Some(now + lockout);
+        self.metrics.auth_lockouts.fetch_add(1, Ordering::Relaxed);
+        println!("Locked out {ip} for {}s", lockout.as_secs_f32());
+        Some(lockout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockouts(max_failures: u32, base: u64, max: u64) -> Lockouts {
+        let config = Config {
+            auth_max_failures: max_failures,
+            auth_lockout: base,
+            auth_lockout_max: max,
+            ..Config::default()
+        };
+        Lockouts::new(&config, Arc::new(Metrics::default()))
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn doubles_the_lockout_up_to_the_max() {
+        let lockouts = lockouts(3, 1000, 8000);
+        assert_eq!(lockouts.record_failure(ip(1)), None);
+        assert_eq!(lockouts.record_failure(ip(1)), None);
+        assert_eq!(lockouts.check(ip(1)), None);
+
+        let expected = [1, 2, 4, 8, 8].map(Duration::from_secs);
+        for lockout in expected {
+            assert_eq!(lockouts.record_failure(ip(1)), Some(lockout));
+        }
+        assert!(lockouts
+            .check(ip(1))
+            .is_some_and(|remaining| remaining <= Duration::from_secs(8)));
+        assert_eq!(lockouts.check(ip(2)), None);
+    }
+
+    #[test]
+    fn disabled_without_max_failures() {
+        let lockouts = lockouts(0, 1000, 8000);
+        for _ in 0..10 {
+            assert_eq!(lockouts.record_failure(ip(1)), None);
+        }
+        assert_eq!(lockouts.check(ip(1)), None);
+    }
+
+    #[test]
+    fn many_failures_do_not_overflow() {
+        let lockouts = lockouts(1, 1000, u64::MAX);
+        let mut last = Duration::ZERO;
+        for _ in 0..100 {
+            last = lockouts.record_failure(ip(1)).unwrap();
+        }
+        assert_eq!(last, Duration::from_secs(1 << 31));
+    }
+
+    #[test]
+    fn forgets_an_ip_after_the_max() {
+        let lockouts = lockouts(2, 10, 50);
+        assert_eq!(lockouts.record_failure(ip(1)), None);
+        assert_eq!(
+            lockouts.record_failure(ip(1)),
+            Some(Duration::from_millis(10))
+        );
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(lockouts.check(ip(1)), None);
+        // The earlier failures no longer count.
+        assert_eq!(lockouts.record_failure(ip(1)), None);
+    }
+}
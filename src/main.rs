@@ -1,14 +1,26 @@
 use dotenvy::dotenv;
 use std::future::IntoFuture;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{atomic::AtomicU64, Arc};
 use tokio::{
     net::TcpListener,
     sync::{mpsc, RwLock},
 };
+mod changes;
+mod cidr;
+mod codec;
 mod config;
 mod context;
+mod events;
+mod lockout;
+mod metrics;
+mod outbox;
+mod ratelimit;
 mod server;
 mod service;
+mod sessions;
+mod tls;
+mod tokens;
 mod websocket;
 mod workers;
 
@@ -25,11 +37,25 @@ static GLOBAL: Jemalloc = Jemalloc;
 async fn main() {
     let _ = dotenv();
 
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {}
+        Some("hash-secret") => {
+            hash_secret_command(args);
+            return;
+        }
+        Some(command) => {
+            eprintln!("unknown command: {command}");
+            eprintln!("usage: jsonkv-server [hash-secret <name> [token]]");
+            std::process::exit(2);
+        }
+    }
+
     let config = config::parse_from_env();
     println!("config: {:?}", config);
 
     let secrets = config::load_secrets(&config.secret_file_path);
-    println!("secrets: {:?}", secrets);
+    println!("secrets: {} loaded", secrets.secret.len());
 
     let listen = config::parse_listen(&config.listen);
     println!("listen: {:?}", listen);
@@ -43,41 +69,112 @@ async fn main() {
     let data = file_save::load_data_from_disk(&config.data_dir_path)
         .await
         .unwrap();
+    // Sequence numbers carry on from the last run, so clients resuming across a restart can't mistake new changes for old ones.
+    let seq = file_save::load_seq(&config.data_dir_path);
 
     let file_save = mpsc::channel(1000);
     let file_listen = mpsc::channel(32);
     let broadcaster = mpsc::channel(32);
+    let audit = mpsc::channel(1000);
 
-    let broadcast = tokio::sync::broadcast::channel(32);
+    let broadcast = tokio::sync::broadcast::channel(config.broadcast_capacity);
+    let changes = Arc::new(RwLock::new(changes::ChangeLog::new(
+        config.replay_buffer_size,
+        seq,
+    )));
 
-    let hashmap = Arc::new(RwLock::new(data));
+    let hashmap = data
+        .into_iter()
+        .map(|(key, value)| (key, service::Entry { value, revision: seq }))
+        .collect();
+    let hashmap = Arc::new(RwLock::new(hashmap));
 
+    let metrics = Arc::new(metrics::Metrics::default());
     let context = Arc::new(context::AppContext {
         config: config.clone(),
         secrets: Arc::new(RwLock::new(secrets)),
-        hashmap: hashmap.clone(),
-        sender_file_save: file_save.0.clone(),
+        secrets_reloaded: tokio::sync::watch::channel(0).0,
+        token_signer: tokens::TokenSigner::new(config::load_token_key()),
         broadcast: broadcast.0.clone(),
+        changes: changes.clone(),
 
         key_service: Arc::new(service::KeyService {
             hashmap: hashmap.clone(),
             sender_file_save: file_save.0.clone(),
             broadcaster: broadcaster.0,
+            sender_audit: audit.0,
+            seq: AtomicU64::new(seq),
         }),
+        metrics: metrics.clone(),
+        sessions: Arc::new(sessions::Sessions::default()),
+        lockouts: lockout::Lockouts::new(&config, metrics.clone()),
+        rate_limits: ratelimit::RateLimits::new(&config, metrics),
     });
 
     let router = server::create_router(context.clone()).await;
     let listener = match listen.clone() {
         config::ListenType::Http(addr) => TcpListener::bind(addr).await.unwrap(),
-        config::ListenType::Unix(_) => todo!(), // tricky task
+        config::ListenType::Unix(path) => todo!("listen on {path}"), // tricky task
+    };
+
+    // TLS is enabled by the certificate and its key.
+    let tls_config = if config.tls_cert_path.is_some() || config.tls_key_path.is_some() {
+        let tls_config = tls::load_tls_config(&config).unwrap_or_else(|err| panic!("TLS: {err}"));
+        println!("TLS: enabled");
+        Some(Arc::new(std::sync::RwLock::new(Arc::new(tls_config))))
+    } else {
+        None
     };
 
     println!("Listening on: {:?}", listen);
+    let server = async {
+        match tls_config.clone() {
+            Some(tls_config) => tls::serve_tls(listener, router, tls_config).await,
+            None => {
+                let _ = axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+                    .into_future()
+                    .await;
+            }
+        }
+    };
     tokio::select! {
-        _ = axum::serve(listener, router).into_future() => (),
-        _ = workers::file_save::save_data_worker(file_save.1, config.data_dir_path.clone(), config.save_interval) => (),
+        _ = server => (),
+        _ = workers::file_save::save_data_worker(file_save.1, config.data_dir_path.clone(), config.save_interval, &context.key_service.seq) => (),
         _ = workers::file_listen::file_listen_worker(&config.data_dir_path, file_listen.0) => (),
         _ = workers::file_read::file_read_worker(&config.data_dir_path, file_listen.1, context.key_service.clone()) => (),
-        _ = workers::broadcaster::worker_broadcaster(broadcaster.1, broadcast.0) => (),
+        _ = workers::broadcaster::worker_broadcaster(broadcaster.1, broadcast.0, changes) => (),
+        _ = workers::audit::audit_worker(audit.1, config.audit_log_path.clone(), config.audit_log_max_size, config.audit_log_max_files) => (),
+        _ = workers::tls_reload::tls_reload_worker(config.clone(), tls_config.clone()) => (),
+        _ = workers::secret_reload::secret_reload_worker(config.secret_file_path.clone(), context.clone()) => (),
     }
 }
+
+/// Print a `secret.toml` entry storing the hash of the token.
+/// A random token is generated if not given.
+fn hash_secret_command(mut args: impl Iterator<Item = String>) {
+    let Some(name) = args.next() else {
+        eprintln!("usage: jsonkv-server hash-secret <name> [token]");
+        std::process::exit(2);
+    };
+    let token = match args.next() {
+        Some(token) => token,
+        None => {
+            let token = config::generate_token();
+            println!("# token: {token}");
+            token
+        }
+    };
+    let secrets = config::Secrets {
+        secret: vec![config::Secret {
+            secret: config::hash_secret(&token),
+            name,
+            description: None,
+            permissions: config::Permission::all(),
+            keys: config::default_scopes(),
+            expires_at: None,
+            client_subject: None,
+            allowed_ips: Vec::new(),
+        }],
+    };
+    print!("{}", toml::to_string(&secrets).unwrap());
+}
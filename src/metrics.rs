@@ -0,0 +1,111 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exposed at `GET /metrics` in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// How many times a websocket client lagged behind the broadcast channel.
+    pub broadcast_lagged: AtomicU64,
+    /// How many changes were skipped by lagging websocket clients.
+    pub broadcast_lagged_changes: AtomicU64,
+    /// The number of connected websocket clients.
+    pub ws_connected: AtomicU64,
+    /// The number of websocket clients which missed the last heartbeat.
+    pub ws_idle: AtomicU64,
+    /// How many websocket clients were disconnected for not responding to pings.
+    pub ws_heartbeat_timeouts: AtomicU64,
+    /// How many authentications failed.
+    pub auth_failures: AtomicU64,
+    /// How many times a remote IP was locked out.
+    pub auth_lockouts: AtomicU64,
+    /// How many authentications were refused during a lockout.
+    pub auth_rejected: AtomicU64,
+    /// How many requests and websocket messages exceeded a rate limit.
+    pub rate_limited: AtomicU64,
+    /// How many websocket connections exceeded the connection limit of their secret.
+    pub ws_connections_rejected: AtomicU64,
+}
+
+impl Metrics {
+    /// Render every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "jsonkv_broadcast_lagged_total",
+            "counter",
+            "Times a websocket client lagged behind the broadcast channel.",
+            self.broadcast_lagged.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_broadcast_lagged_changes_total",
+            "counter",
+            "Changes skipped by lagging websocket clients.",
+            self.broadcast_lagged_changes.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_ws_connected",
+            "gauge",
+            "Connected websocket clients.",
+            self.ws_connected.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_ws_idle",
+            "gauge",
+            "Websocket clients which missed the last heartbeat.",
+            self.ws_idle.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_ws_heartbeat_timeouts_total",
+            "counter",
+            "Websocket clients disconnected for not responding to pings.",
+            self.ws_heartbeat_timeouts.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_auth_failures_total",
+            "counter",
+            "Failed authentications.",
+            self.auth_failures.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_auth_lockouts_total",
+            "counter",
+            "Times a remote IP was locked out after failing to authenticate.",
+            self.auth_lockouts.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_auth_rejected_total",
+            "counter",
+            "Authentications refused during a lockout.",
+            self.auth_rejected.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_rate_limited_total",
+            "counter",
+            "Requests and websocket messages which exceeded a rate limit.",
+            self.rate_limited.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "jsonkv_ws_connections_rejected_total",
+            "counter",
+            "Websocket connections which exceeded the connection limit of their secret.",
+            self.ws_connections_rejected.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// Merges a pending message with a newer one for the same key.
+pub trait Coalesce {
+    fn coalesce(self, newer: Self) -> Self;
+}
+
+enum Slot<K, T> {
+    Message(T),
+    /// The pending message is stored in `State::latest`.
+    Latest(K),
+}
+
+struct State<K, T> {
+    queue: VecDeque<Slot<K, T>>,
+    /// The number of `Slot::Message` in the queue.
+    messages: usize,
+    latest: HashMap<K, T>,
+    /// The minimum interval between messages of a key.
+    intervals: HashMap<K, Duration>,
+    /// When the next message of a throttled key may be sent.
+    next_at: HashMap<K, Instant>,
+    closed: bool,
+}
+
+/// A per-connection queue of outgoing messages. \
+/// Keyed messages are coalesced so only the latest pending one per key is kept,
+/// which lets a slow client jump straight to the current state instead of
+/// building a backlog of stale intermediate ones.
+pub struct Outbox<K, T> {
+    state: Mutex<State<K, T>>,
+    notify: Notify,
+    /// The maximum number of pending unkeyed messages.
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, T: Coalesce> Outbox<K, T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                messages: 0,
+                latest: HashMap::new(),
+                intervals: HashMap::new(),
+                next_at: HashMap::new(),
+                closed: false,
+            }),
+            notify: Notify::new(),
+            capacity,
+        }
+    }
+
+    /// Queue a message in order.
+    /// Returns `false` if the outbox is closed or full.
+    pub fn push(&self, message: T) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed || state.messages >= self.capacity {
+                return false;
+            }
+            state.messages += 1;
+            state.queue.push_back(Slot::Message(message));
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    /// Queue the latest message of the key, coalescing it with a pending one.
+    /// Returns `false` if the outbox is closed.
+    pub fn push_latest(&self, key: K, message: T) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return false;
+            }
+            match state.latest.remove(&key) {
+                Some(pending) => {
+                    state.latest.insert(key, pending.coalesce(message));
+                }
+                None => {
+                    state.latest.insert(key.clone(), message);
+                    state.queue.push_back(Slot::Latest(key));
+                }
+            }
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    /// Send at most one message of the key per `interval`. `None` removes the limit.
+    pub fn throttle(&self, key: K, interval: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        match interval {
+            Some(interval) => {
+                state.intervals.insert(key, interval);
+            }
+            None => {
+                state.intervals.remove(&key);
+                state.next_at.remove(&key);
+            }
+        }
+    }
+
+    /// Stop accepting messages. `recv` returns `None` once the pending ones are drained.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next message which is ready to be sent.
+    /// This is cancel safe, a message is only removed when returned.
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            let wake_at = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let mut wake_at: Option<Instant> = None;
+                let mut ready = None;
+                for (index, slot) in state.queue.iter().enumerate() {
+                    match slot {
+                        Slot::Message(_) => {
+                            ready = Some(index);
+                            break;
+                        }
+                        Slot::Latest(key) => match state.next_at.get(key) {
+                            Some(next_at) if *next_at > now => {
+                                wake_at = Some(wake_at.map_or(*next_at, |at| at.min(*next_at)));
+                            }
+                            _ => {
+                                ready = Some(index);
+                                break;
+                            }
+                        },
+                    }
+                }
+
+                if let Some(index) = ready {
+                    match state.queue.remove(index).unwrap() {
+                        Slot::Message(message) => {
+                            state.messages -= 1;
+                            return Some(message);
+                        }
+                        Slot::Latest(key) => {
+                            if let Some(interval) = state.intervals.get(&key).copied() {
+                                state.next_at.insert(key.clone(), now + interval);
+                            }
+                            return state.latest.remove(&key);
+                        }
+                    }
+                }
+                if state.closed && state.queue.is_empty() {
+                    return None;
+                }
+                wake_at
+            };
+
+            match wake_at {
+                Some(wake_at) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {},
+                        _ = tokio::time::sleep_until(wake_at) => {},
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Records every value it was coalesced from, oldest first.
+    #[derive(Debug, PartialEq)]
+    struct Values(Vec<u32>);
+
+    impl Coalesce for Values {
+        fn coalesce(mut self, mut newer: Self) -> Self {
+            self.0.append(&mut newer.0);
+            self
+        }
+    }
+
+    fn values(values: &[u32]) -> Values {
+        Values(values.to_vec())
+    }
+
+    /// The next message if one is ready right away.
+    fn try_recv(outbox: &Outbox<&'static str, Values>) -> Option<Values> {
+        outbox.recv().now_or_never().flatten()
+    }
+
+    #[test]
+    fn coalesces_pending_messages_of_a_key() {
+        let outbox = Outbox::new(8);
+        outbox.push_latest("a", values(&[1]));
+        outbox.push_latest("b", values(&[2]));
+        outbox.push_latest("a", values(&[3]));
+        assert_eq!(try_recv(&outbox), Some(values(&[1, 3])));
+        assert_eq!(try_recv(&outbox), Some(values(&[2])));
+        assert_eq!(try_recv(&outbox), None);
+
+        // Once sent, the next message of the key is queued again.
+        outbox.push_latest("a", values(&[4]));
+        assert_eq!(try_recv(&outbox), Some(values(&[4])));
+    }
+
+    #[test]
+    fn keeps_the_order_of_keyed_and_unkeyed_messages() {
+        let outbox = Outbox::new(8);
+        outbox.push(values(&[1]));
+        outbox.push_latest("a", values(&[2]));
+        outbox.push(values(&[3]));
+        // A coalesced message keeps the place of the pending one.
+        outbox.push_latest("a", values(&[4]));
+        outbox.push(values(&[5]));
+        assert_eq!(try_recv(&outbox), Some(values(&[1])));
+        assert_eq!(try_recv(&outbox), Some(values(&[2, 4])));
+        assert_eq!(try_recv(&outbox), Some(values(&[3])));
+        assert_eq!(try_recv(&outbox), Some(values(&[5])));
+        assert_eq!(try_recv(&outbox), None);
+    }
+
+    #[test]
+    fn bounds_only_unkeyed_messages() {
+        let outbox = Outbox::new(2);
+        assert!(outbox.push(values(&[1])));
+        assert!(outbox.push(values(&[2])));
+        assert!(!outbox.push(values(&[3])));
+        assert!(outbox.push_latest("a", values(&[4])));
+
+        assert_eq!(try_recv(&outbox), Some(values(&[1])));
+        assert!(outbox.push(values(&[5])));
+    }
+
+    #[tokio::test]
+    async fn throttles_a_key() {
+        let interval = Duration::from_millis(100);
+        let outbox = Outbox::new(8);
+        outbox.throttle("a", Some(interval));
+        outbox.push_latest("a", values(&[1]));
+        assert_eq!(try_recv(&outbox), Some(values(&[1])));
+
+        // Too soon, so it waits and coalesces, while the others pass it.
+        outbox.push_latest("a", values(&[2]));
+        outbox.push_latest("b", values(&[3]));
+        outbox.push(values(&[4]));
+        outbox.push_latest("a", values(&[5]));
+        assert_eq!(try_recv(&outbox), Some(values(&[3])));
+        assert_eq!(try_recv(&outbox), Some(values(&[4])));
+        assert_eq!(try_recv(&outbox), None);
+
+        let started = Instant::now();
+        assert_eq!(outbox.recv().await, Some(values(&[2, 5])));
+        assert!(started.elapsed() >= interval / 2);
+
+        // Without the limit, it's sent right away again.
+        outbox.throttle("a", None);
+        outbox.push_latest("a", values(&[6]));
+        assert_eq!(try_recv(&outbox), Some(values(&[6])));
+    }
+
+    #[tokio::test]
+    async fn drains_after_close() {
+        let outbox = Outbox::new(8);
+        outbox.push(values(&[1]));
+        outbox.push_latest("a", values(&[2]));
+        outbox.close();
+        assert!(!outbox.push(values(&[3])));
+        assert!(!outbox.push_latest("a", values(&[4])));
+        assert_eq!(outbox.recv().await, Some(values(&[1])));
+        assert_eq!(outbox.recv().await, Some(values(&[2])));
+        assert_eq!(outbox.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_a_waiting_receiver() {
+        let outbox = std::sync::Arc::new(Outbox::<&'static str, Values>::new(8));
+        let receiver = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.recv().await }
+        });
+        tokio::task::yield_now().await;
+        outbox.close();
+        let received = tokio::time::timeout(Duration::from_secs(1), receiver).await;
+        assert_eq!(received.unwrap().unwrap(), None);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// Buckets which aren't refilling are forgotten past this many.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket per key, refilled at `rate` tokens per second up to `burst`.
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// `rate` of `0` disables the limit.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token of the key. Returns the time until one is available if there's none left.
+    pub fn acquire(&self, key: K) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            // A full bucket is the same as a new one.
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * self.rate
+                    < self.burst
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The request rate limits of every secret and every remote IP.
+pub struct RateLimits {
+    secrets: RateLimiter<String>,
+    ips: RateLimiter<IpAddr>,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimits {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            secrets: RateLimiter::new(config.rate_limit_secret, config.rate_limit_secret_burst),
+            ips: RateLimiter::new(config.rate_limit_ip, config.rate_limit_ip_burst),
+            metrics,
+        }
+    }
+
+    /// Take a token of the IP, then of the secret if authenticated.
+    /// Returns the time until the request would be allowed if either is exhausted.
+    pub fn acquire(&self, secret: Option<&str>, ip: IpAddr) -> Result<(), Duration> {
+        let result = self.ips.acquire(ip).and_then(|()| match secret {
+            Some(name) => self.secrets.acquire(name.to_owned()),
+            None => Ok(()),
+        });
+        if result.is_err() {
+            self.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_the_burst_then_waits_for_a_token() {
+        let limiter = RateLimiter::new(10.0, 3.0);
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire("a"), Ok(()));
+        }
+        let retry_after = limiter.acquire("a").unwrap_err();
+        assert!(retry_after <= Duration::from_millis(100));
+        assert!(retry_after > Duration::from_millis(50));
+    }
+
+    #[test]
+    fn limits_each_key_separately() {
+        let limiter = RateLimiter::new(1.0, 1.0);
+        assert_eq!(limiter.acquire("a"), Ok(()));
+        assert!(limiter.acquire("a").is_err());
+        assert_eq!(limiter.acquire("b"), Ok(()));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(100.0, 1.0);
+        assert_eq!(limiter.acquire("a"), Ok(()));
+        assert!(limiter.acquire("a").is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.acquire("a"), Ok(()));
+    }
+
+    #[test]
+    fn disabled_without_rate() {
+        let limiter = RateLimiter::new(0.0, 1.0);
+        for _ in 0..100 {
+            assert_eq!(limiter.acquire("a"), Ok(()));
+        }
+    }
+
+    #[test]
+    fn allows_at_least_one_request() {
+        let limiter = RateLimiter::new(1.0, 0.0);
+        assert_eq!(limiter.acquire("a"), Ok(()));
+        assert!(limiter.acquire("a").is_err());
+    }
+}
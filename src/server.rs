@@ -1,50 +1,193 @@
-use crate::websocket::handle_websocket;
+use crate::codec::Encoding;
+use crate::events::{stream_changes, stream_events};
+use crate::websocket::{handle_websocket, Connection, OutputMode, PROTOCOL_TOKEN_PREFIX};
 use axum::{
     body::Body,
-    extract::{Path, Request, State, WebSocketUpgrade},
-    http::{HeaderValue, Method, StatusCode},
+    extract::{ConnectInfo, Path, Query, Request, State, WebSocketUpgrade},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
-    Json, Router,
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
 use axum_extra::{
     headers::{self},
     TypedHeader,
 };
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tower_http::cors::CorsLayer;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, Instant};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::{
+    changes::{unix_millis, ChangeLog, Event},
+    cidr::Cidr,
+    config::{
+        default_scopes, generate_token, hash_secret, save_secrets, Config, CorsPolicy, Permission,
+        Secret, SecretInfo,
+    },
+    context::AppContext,
+    service::{Author, Entry, KeyServiceError, KeyServiceTrait},
+    tls::ClientCert,
+    tokens::{authenticate, Claims, TOKEN_NAME_PREFIX},
+    workers::audit::read_audit_log,
+};
+
+/// The header carrying the revision of a key.
+const REVISION_HEADER: &str = "X-Revision";
+/// The `seq` of the latest change, sent with `/changes`.
+const LATEST_SEQ_HEADER: &str = "X-Latest-Seq";
+/// The lowest `since` which `/changes` still serves.
+const OLDEST_SINCE_HEADER: &str = "X-Oldest-Since";
 
-use crate::{config::Secrets, context::AppContext, service::KeyServiceTrait};
 pub async fn create_router(context: Arc<AppContext>) -> Router {
+    let mut data_routes = Router::new().route(
+        "/data/:key",
+        get(get_key)
+            .post(post_key)
+            .put(put_key)
+            .patch(patch_key)
+            .delete(delete_key),
+    );
+    if context.config.enable_list {
+        data_routes = data_routes.route("/list", get(list_keys));
+    }
+    data_routes = data_routes
+        .route("/events", get(events))
+        .route("/changes", get(changes))
+        .route("/publish/:channel", post(publish));
+    let admin_routes = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/clients", get(list_clients))
+        .route("/clients/:id", delete(kick_client))
+        .route("/admin/secrets", get(list_secrets).post(create_secret))
+        .route("/admin/secrets/:name", delete(delete_secret))
+        .route("/admin/tokens", post(create_token))
+        .route("/admin/audit", get(audit_log));
+
+    // auth header doesn't work in websocket.
+    let listen_routes = Router::new()
+        .route("/listen", get(ws_key))
+        .route("/listen/:key", get(ws_key));
+
+    let config = &context.config;
     Router::new()
         .route("/", get(index))
         .merge(
-            Router::new()
-                .route(
-                    "/data/:key",
-                    get(get_key).post(post_key).put(put_key).patch(patch_key),
-                )
-                .route("/list", get(list_keys))
+            data_routes
+                .route_layer(middleware::from_fn_with_state(
+                    context.clone(),
+                    rate_limit_layer,
+                ))
                 .route_layer(middleware::from_fn_with_state(context.clone(), auth_layer))
-                .layer(CorsLayer::permissive())
-                .route("/listen", get(ws_key)) // auth header doesn't work in websocket.
-                .with_state(context),
+                .layer(cors_layer(&config.cors_data))
+                .with_state(context.clone()),
+        )
+        .merge(
+            admin_routes
+                .route_layer(middleware::from_fn_with_state(context.clone(), auth_layer))
+                .layer(cors_layer(&config.cors_admin))
+                .with_state(context.clone()),
+        )
+        .merge(
+            listen_routes
+                .layer(cors_layer(&config.cors_listen))
+                .with_state(context.clone()),
         )
         .fallback(handle_404)
 }
 
+/// Build the CORS layer of a route group.
+/// Panics on an invalid policy, as it's only called at startup.
+fn cors_layer(policy: &CorsPolicy) -> CorsLayer {
+    if policy.permissive {
+        return CorsLayer::permissive();
+    }
+    let is_any = |list: &[String]| list.iter().any(|item| item == "*");
+    if policy.credentials
+        && (is_any(&policy.origins) || is_any(&policy.methods) || is_any(&policy.headers))
+    {
+        panic!("CORS credentials can't be allowed with `*` origins, methods or headers");
+    }
+
+    let origins = if is_any(&policy.origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(policy.origins.iter().map(|origin| {
+            HeaderValue::from_str(origin)
+                .unwrap_or_else(|_| panic!("invalid CORS origin: {origin}"))
+        }))
+    };
+    let methods = if is_any(&policy.methods) {
+        AllowMethods::any()
+    } else {
+        AllowMethods::list(policy.methods.iter().map(|method| {
+            Method::from_bytes(method.to_uppercase().as_bytes())
+                .unwrap_or_else(|_| panic!("invalid CORS method: {method}"))
+        }))
+    };
+    let headers = if is_any(&policy.headers) {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(policy.headers.iter().map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .unwrap_or_else(|_| panic!("invalid CORS header: {name}"))
+        }))
+    };
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(policy.credentials)
+        .expose_headers(
+            [REVISION_HEADER, LATEST_SEQ_HEADER, OLDEST_SINCE_HEADER]
+                .map(|name| HeaderName::from_bytes(name.as_bytes()).unwrap()),
+        );
+    if let Some(max_age) = policy.max_age {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+    layer
+}
+
 async fn auth_layer(
     State(context): State<Arc<AppContext>>,
-    request: Request,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    mut request: Request,
     next: Next,
 ) -> Response {
+    let ip = client_ip(&context.config, remote_addr, request.headers());
+    request.extensions_mut().insert(ClientIp(ip));
     if request.method() == Method::OPTIONS {
         return next.run(request).await;
     }
+    // Handlers check the permissions of the secret.
     if let Some(auth) = request.headers().get("Authorization") {
-        if check_auth(auth, &context.secrets).await {
+        if let Some(remaining) = context.lockouts.check(ip) {
+            return too_many_requests(remaining);
+        }
+        if let Some(secret) = check_auth(auth, &context)
+            .await
+            .and_then(|secret| secret.allowed_from(ip))
+        {
+            request.extensions_mut().insert(secret);
+            return next.run(request).await;
+        }
+        if let Some(lockout) = context.lockouts.record_failure(ip) {
+            return too_many_requests(lockout);
+        }
+    } else if let Some(secret) =
+        check_client_cert(request.extensions().get::<ClientCert>(), &context)
+            .await
+            .and_then(|secret| secret.allowed_from(ip))
+    {
+        request.extensions_mut().insert(secret);
+        return next.run(request).await;
+    } else if request.method() == Method::GET {
+        // Without a token, only the public keys can be read.
+        if let Some(secret) = context.config.anonymous_secret() {
+            request.extensions_mut().insert(secret);
             return next.run(request).await;
         }
     }
@@ -55,16 +198,100 @@ async fn auth_layer(
         .unwrap()
 }
 
-async fn check_auth(auth: &HeaderValue, secrets: &Arc<RwLock<Secrets>>) -> bool {
-    let auth_str = auth.to_str();
-    if auth_str.is_err() {
-        return false;
+/// Limit the writes of each secret and remote IP. It runs after `auth_layer`.
+async fn rate_limit_layer(
+    State(context): State<Arc<AppContext>>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_write = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if is_write {
+        let name = request
+            .extensions()
+            .get::<Secret>()
+            .map(|secret| secret.name.clone());
+        if let Err(retry_after) = context.rate_limits.acquire(name.as_deref(), ip) {
+            return too_many_requests(retry_after);
+        }
     }
-    let auth = auth_str.unwrap();
+    next.run(request).await
+}
+
+/// The address of the client, added to the requests by `auth_layer`.
+#[derive(Debug, Clone, Copy)]
+struct ClientIp(IpAddr);
+
+/// The address of the client. Behind a trusted proxy, it's the rightmost address of
+/// `X-Forwarded-For` which isn't a trusted proxy itself.
+fn client_ip(config: &Config, remote_addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let is_trusted = |ip: IpAddr| {
+        config
+            .trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(ip))
+    };
+    let mut ip = remote_addr.ip().to_canonical();
+    if !is_trusted(ip) {
+        return ip;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    for addr in forwarded.into_iter().rev() {
+        let Ok(addr) = addr.parse::<IpAddr>() else {
+            break;
+        };
+        ip = addr.to_canonical();
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    ip
+}
+
+/// Refuse a request until `retry_after`, e.g. an IP which failed to authenticate too often.
+fn too_many_requests(retry_after: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(
+            header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        )],
+        "Too Many Requests",
+    )
+        .into_response()
+}
+
+/// Authenticate with the client certificate of the TLS connection, if any.
+async fn check_client_cert(cert: Option<&ClientCert>, context: &AppContext) -> Option<Secret> {
+    let cert = cert?;
+    let secrets = context.secrets.read().await;
+    secrets.by_client_subject(&cert.subject).cloned()
+}
+
+async fn check_auth(auth: &HeaderValue, context: &AppContext) -> Option<Secret> {
+    let auth = auth.to_str().ok()?;
     // Trim the leading "Bearer " from the auth string.
     let auth = auth.trim_start_matches("Bearer ");
-    let secrets = secrets.read().await;
-    secrets.contains_key(auth)
+    let secrets = context.secrets.read().await;
+    authenticate(&secrets, &context.token_signer, auth)
+}
+
+/// Reject a request the secret isn't allowed to make.
+/// Unauthenticated requests get `401`, since they may succeed with a token.
+fn deny(secret: &Secret) -> Response {
+    if secret.is_anonymous() {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
 }
 
 async fn index() -> impl IntoResponse {
@@ -75,79 +302,824 @@ async fn handle_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found")
 }
 
+#[derive(Deserialize)]
+struct GetQuery {
+    /// Hold the request open up to this many seconds until the key changes.
+    wait: Option<u64>,
+    /// The revision the client already has. Defaults to the current one.
+    after: Option<u64>,
+}
+
+/// Get the value of the key, with its revision in the `X-Revision` header.
+///
+/// With `?wait=`, the request is held open until the key changes past `?after=`,
+/// and `304 Not Modified` is returned if the timeout expires first.
 async fn get_key(
     State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
     Path(key): Path<String>,
-) -> impl IntoResponse {
-    match context.key_service.get_key(&key).await {
-        Ok(value) => (StatusCode::OK, value.to_string()),
-        Err(_) => (StatusCode::NOT_FOUND, "Not Found".to_owned()),
+    Query(query): Query<GetQuery>,
+) -> Response {
+    if !secret.allows(Permission::Read, &key) {
+        return deny(&secret);
     }
+    let Some(wait) = query.wait else {
+        return match context.key_service.get_entry(&key).await {
+            Ok(entry) => entry_response(entry),
+            Err(_) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        };
+    };
+
+    // Subscribe before reading the entry, so a change in between isn't missed.
+    let mut receiver = context.broadcast.subscribe();
+    let entry = match context.key_service.get_entry(&key).await {
+        Ok(entry) => entry,
+        Err(_) => return (StatusCode::NOT_FOUND, "Not Found").into_response(),
+    };
+    let after = query.after.unwrap_or(entry.revision);
+    if entry.revision > after {
+        return entry_response(entry);
+    }
+
+    let timeout =
+        Duration::from_secs(wait).min(Duration::from_millis(context.config.long_poll_max_wait));
+    let deadline = Instant::now() + timeout;
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(Event::Change(change))) if change.key == key && change.seq > after => {
+                return entry_response(Entry {
+                    value: change.value.unwrap_or_default(),
+                    revision: change.seq,
+                });
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(RecvError::Lagged(_))) => {
+                // The change may have been skipped, check the current state instead.
+                if let Ok(entry) = context.key_service.get_entry(&key).await {
+                    if entry.revision > after {
+                        return entry_response(entry);
+                    }
+                }
+            }
+            Ok(Err(RecvError::Closed)) | Err(_) => {
+                return (
+                    StatusCode::NOT_MODIFIED,
+                    [(REVISION_HEADER, after.to_string())],
+                )
+                    .into_response();
+            }
+        }
+    }
+}
+
+fn entry_response(entry: Entry) -> Response {
+    (
+        StatusCode::OK,
+        [(REVISION_HEADER, entry.revision.to_string())],
+        entry.value.to_string(),
+    )
+        .into_response()
 }
 
 async fn post_key(
     State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     Path(key): Path<String>,
     Json(value): Json<serde_json::Value>,
-) -> impl IntoResponse {
-    match context.key_service.post_key(&key, value.clone()).await {
+) -> Response {
+    if !secret.allows(Permission::Write, &key) {
+        return deny(&secret);
+    }
+    match context
+        .key_service
+        .post_key(&key, value.clone(), &Author::new(&secret, ip))
+        .await
+    {
         Ok(_) => (StatusCode::OK, value.to_string()),
         Err(_) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Internal Server Error".to_owned(),
         ),
     }
+    .into_response()
 }
 
 async fn put_key(
     State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     Path(key): Path<String>,
     Json(value): Json<serde_json::Value>,
-) -> impl IntoResponse {
-    match context.key_service.put_key(&key, value.clone()).await {
+) -> Response {
+    if !secret.allows(Permission::Write, &key) {
+        return deny(&secret);
+    }
+    match context
+        .key_service
+        .put_key(&key, value.clone(), &Author::new(&secret, ip))
+        .await
+    {
         Ok(_) => (StatusCode::OK, value.to_string()),
         Err(_) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Internal Server Error".to_owned(),
         ),
     }
+    .into_response()
 }
 
 async fn patch_key(
     State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     Path(key): Path<String>,
     Json(value): Json<serde_json::Value>,
-) -> impl IntoResponse {
-    match context.key_service.patch_key(&key, value.clone()).await {
+) -> Response {
+    if !secret.allows(Permission::Write, &key) {
+        return deny(&secret);
+    }
+    match context
+        .key_service
+        .patch_key(&key, value.clone(), &Author::new(&secret, ip))
+        .await
+    {
         Ok(_) => (StatusCode::OK, value.to_string()),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Internal Server Error: {e:?}"),
         ),
     }
+    .into_response()
+}
+
+async fn delete_key(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    Path(key): Path<String>,
+) -> Response {
+    if !secret.allows(Permission::Delete, &key) {
+        return deny(&secret);
+    }
+    match context
+        .key_service
+        .delete_key(&key, &Author::new(&secret, ip))
+        .await
+    {
+        Ok(_) => (StatusCode::OK, "OK".to_owned()),
+        Err(KeyServiceError::KeyNotFound) => (StatusCode::NOT_FOUND, "Not Found".to_owned()),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".to_owned(),
+        ),
+    }
+    .into_response()
+}
+
+async fn publish(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Path(channel): Path<String>,
+    Json(value): Json<serde_json::Value>,
+) -> Response {
+    if !secret.allows(Permission::Write, &channel) {
+        return deny(&secret);
+    }
+    match context.key_service.publish(&channel, value).await {
+        Ok(_) => (StatusCode::OK, "OK".to_owned()),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".to_owned(),
+        ),
+    }
+    .into_response()
 }
 
-async fn list_keys(State(context): State<Arc<AppContext>>) -> impl IntoResponse {
+/// List the keys in the scopes of the secret.
+async fn list_keys(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    if secret.is_anonymous() || !secret.has_permission(Permission::Read) {
+        return deny(&secret);
+    }
     match context.key_service.list_keys().await {
-        Ok(list) => (StatusCode::OK, serde_json::to_string(&list).unwrap()),
+        Ok(mut list) => {
+            list.retain(|key| secret.in_scope(key));
+            (StatusCode::OK, serde_json::to_string(&list).unwrap())
+        }
         Err(_) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Internal Server Error".to_owned(),
         ),
     }
+    .into_response()
 }
 
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Comma separated keys to stream.
+    keys: String,
+}
+
+/// Stream the changes of the keys as Server-Sent Events.
+async fn events(
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    let keys = split_keys(&query.keys);
+    if keys.is_empty() {
+        return (StatusCode::BAD_REQUEST, "keys is required").into_response();
+    }
+    if !keys.iter().all(|key| secret.allows(Permission::Read, key)) {
+        return deny(&secret);
+    }
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    stream_events(context, secret, keys, last_event_id)
+        .await
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    /// Only the changes after this `seq`. Every buffered change if omitted.
+    since: Option<u64>,
+    /// Keep the response open and stream the changes as NDJSON.
+    #[serde(default)]
+    stream: bool,
+}
+
+/// The ordered log of every change after `since`, across the keys in the scopes of the secret.
+/// Returns `410 Gone` if the change log no longer covers `since`. The responses carry the
+/// latest `seq` and the oldest `since` still covered, so a client can resync and continue.
+async fn changes(
+    Query(query): Query<ChangesQuery>,
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    // The authors of the changes aren't public.
+    if secret.is_anonymous() || !secret.has_permission(Permission::Read) {
+        return deny(&secret);
+    }
+    let gone = |log: &ChangeLog| {
+        (
+            StatusCode::GONE,
+            seq_headers(log),
+            "The changes since the given seq are no longer available",
+        )
+            .into_response()
+    };
+    if query.stream {
+        return match stream_changes(context.clone(), secret, query.since).await {
+            Some(stream) => (
+                seq_headers(&*context.changes.read().await),
+                [(header::CONTENT_TYPE, "application/x-ndjson")],
+                Body::from_stream(stream),
+            )
+                .into_response(),
+            None => gone(&*context.changes.read().await),
+        };
+    }
+    let log = context.changes.read().await;
+    let since = query.since.unwrap_or_else(|| log.oldest_since());
+    match log.since(since) {
+        Some(mut changes) => {
+            changes.retain(|change| secret.in_scope(&change.key));
+            (seq_headers(&log), Json(changes)).into_response()
+        }
+        None => gone(&log),
+    }
+}
+
+fn seq_headers(log: &ChangeLog) -> [(&'static str, String); 2] {
+    [
+        (LATEST_SEQ_HEADER, log.latest_seq().to_string()),
+        (OLDEST_SINCE_HEADER, log.oldest_since().to_string()),
+    ]
+}
+
+async fn metrics(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        context.metrics.render(),
+    )
+        .into_response()
+}
+
+async fn list_clients(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    Json(context.sessions.list()).into_response()
+}
+
+async fn kick_client(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Path(id): Path<u64>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    match context.sessions.get(id) {
+        Some(session) => {
+            session.kick("disconnected by admin");
+            (StatusCode::OK, "OK")
+        }
+        None => (StatusCode::NOT_FOUND, "Not Found"),
+    }
+    .into_response()
+}
+
+async fn list_secrets(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    let secrets = context.secrets.read().await;
+    let list: Vec<SecretInfo> = secrets.secret.iter().map(Secret::info).collect();
+    Json(list).into_response()
+}
+
+#[derive(Deserialize)]
+struct CreateSecret {
+    name: String,
+    description: Option<String>,
+    #[serde(default = "Permission::all")]
+    permissions: Vec<Permission>,
+    #[serde(default = "default_scopes")]
+    keys: Vec<String>,
+    /// Unix time in milliseconds.
+    expires_at: Option<u64>,
+    client_subject: Option<String>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+}
+
+/// Create a secret with a random token, and save it to the secret file.
+/// Only the hash is stored, so the token is returned this once.
+async fn create_secret(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Json(request): Json<CreateSecret>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    if request.name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "name is required").into_response();
+    }
+    if request.name.starts_with(TOKEN_NAME_PREFIX) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "name can't start with `{TOKEN_NAME_PREFIX}`, which is reserved for signed tokens"
+            ),
+        )
+            .into_response();
+    }
+    for network in &request.allowed_ips {
+        if let Err(err) = network.parse::<Cidr>() {
+            return (StatusCode::BAD_REQUEST, err).into_response();
+        }
+    }
+
+    let mut secrets = context.secrets.write().await;
+    if secrets.secret.iter().any(|s| s.name == request.name) {
+        return (
+            StatusCode::CONFLICT,
+            "A secret with this name already exists",
+        )
+            .into_response();
+    }
+    if request.client_subject.is_some()
+        && secrets
+            .secret
+            .iter()
+            .any(|s| s.client_subject == request.client_subject)
+    {
+        return (
+            StatusCode::CONFLICT,
+            "A secret with this client subject already exists",
+        )
+            .into_response();
+    }
+    let token = generate_token();
+    let created = Secret {
+        secret: hash_secret(&token),
+        name: request.name,
+        description: request.description,
+        permissions: request.permissions,
+        keys: request.keys,
+        expires_at: request.expires_at,
+        client_subject: request.client_subject,
+        allowed_ips: request.allowed_ips,
+    };
+    let info = created.info();
+    let mut updated = secrets.clone();
+    updated.secret.push(created);
+    if let Err(err) = save_secrets(&context.config.secret_file_path, &updated) {
+        println!("Unable to save the secrets: {err}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+    }
+    *secrets = updated;
+    println!("Secret `{}` created.", info.name);
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "token": token, "secret": info })),
+    )
+        .into_response()
+}
+
+/// Delete the secret from the secret file, disconnecting the sessions using it.
+async fn delete_secret(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Path(name): Path<String>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+
+    let mut secrets = context.secrets.write().await;
+    let mut updated = secrets.clone();
+    updated.secret.retain(|s| s.name != name);
+    if updated.secret.len() == secrets.secret.len() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+    if let Err(err) = save_secrets(&context.config.secret_file_path, &updated) {
+        println!("Unable to save the secrets: {err}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+    }
+    context.sessions.apply_secrets(&updated);
+    *secrets = updated;
+    context
+        .secrets_reloaded
+        .send_modify(|version| *version += 1);
+    println!("Secret `{name}` deleted.");
+    (StatusCode::OK, "OK").into_response()
+}
+
+#[derive(Deserialize)]
+struct CreateToken {
+    /// Shown in `/clients`.
+    name: String,
+    #[serde(default = "default_scopes")]
+    keys: Vec<String>,
+    #[serde(default = "default_token_permissions")]
+    permissions: Vec<Permission>,
+    /// The lifetime of the token, in seconds.
+    expires_in: u64,
+}
+
+fn default_token_permissions() -> Vec<Permission> {
+    vec![Permission::Read]
+}
+
+/// Mint a short-lived signed token, which isn't stored anywhere.
+async fn create_token(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Json(request): Json<CreateToken>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    if request.permissions.contains(&Permission::Admin) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Signed tokens can't have the admin permission",
+        )
+            .into_response();
+    }
+    if request.expires_in > context.config.token_max_lifetime {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "expires_in can't exceed {} seconds",
+                context.config.token_max_lifetime
+            ),
+        )
+            .into_response();
+    }
+    let Some(exp) = request
+        .expires_in
+        .checked_mul(1000)
+        .and_then(|expires_in| unix_millis().checked_add(expires_in))
+    else {
+        return (StatusCode::BAD_REQUEST, "expires_in is too large").into_response();
+    };
+    let claims = Claims {
+        name: request.name,
+        exp,
+        keys: request.keys,
+        permissions: request.permissions,
+    };
+    let token = context.token_signer.sign(&claims);
+    println!(
+        "Signed token `{}` created by `{}`.",
+        claims.name, secret.name
+    );
+    Json(serde_json::json!({ "token": token, "expires_at": claims.exp })).into_response()
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    key: Option<String>,
+    /// The name of the secret.
+    name: Option<String>,
+    /// Unix time in milliseconds.
+    since: Option<u64>,
+    /// Unix time in milliseconds.
+    until: Option<u64>,
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/// Query the audit log. The latest `limit` matching records are returned, oldest first.
+async fn audit_log(
+    State(context): State<Arc<AppContext>>,
+    Extension(secret): Extension<Secret>,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    if !secret.has_permission(Permission::Admin) {
+        return deny(&secret);
+    }
+    let path = context.config.audit_log_path.clone();
+    let max_files = context.config.audit_log_max_files;
+    // The files may be large, keep their reading off the async workers.
+    let records = tokio::task::spawn_blocking(move || {
+        read_audit_log(&path, max_files, query.since, query.limit, |record| {
+            query.key.as_ref().is_none_or(|key| &record.key == key)
+                && query.name.as_ref().is_none_or(|name| &record.name == name)
+                && query.since.is_none_or(|since| record.time >= since)
+                && query.until.is_none_or(|until| record.time <= until)
+        })
+    })
+    .await
+    .unwrap();
+    match records {
+        Ok(records) => Json(records).into_response(),
+        Err(err) => {
+            println!("Unable to read the audit log: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ListenQuery {
+    token: Option<String>,
+    /// Comma separated keys to subscribe to once authenticated.
+    keys: Option<String>,
+    #[serde(default)]
+    mode: OutputMode,
+}
+
+/// Browsers can't set the `Authorization` header on websockets, so the token may be given
+/// as `?token=` or as a `bearer.<token>` subprotocol. Without it, the client has to send
+/// `Authenticate` before `ws_auth_timeout`.
+///
+/// The key of `/listen/:key`, or the keys of `?keys=a,b`, are subscribed once authenticated.
+#[allow(clippy::too_many_arguments)]
 async fn ws_key(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    client_cert: Option<Extension<ClientCert>>,
+    key: Option<Path<String>>,
+    Query(query): Query<ListenQuery>,
+    headers: HeaderMap,
     State(context): State<Arc<AppContext>>,
-) -> impl IntoResponse {
+) -> Response {
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
         String::from("Unknown browser")
     };
 
+    // Browsers don't apply CORS to websockets, so a page from any origin could connect with the
+    // cookies or client certificate of the user. Clients which aren't browsers send no `Origin`.
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let allowed = origin
+            .to_str()
+            .is_ok_and(|origin| context.config.cors_listen.allows_origin(origin));
+        if !allowed {
+            println!("WS: `{user_agent}` rejected, origin not allowed: {origin:?}");
+            return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+        }
+    }
+
+    let token = query.token.or_else(|| protocol_token(&headers));
+    let ip = client_ip(&context.config, remote_addr, &headers);
+    let secret = match token {
+        Some(token) => {
+            if let Some(remaining) = context.lockouts.check(ip) {
+                return too_many_requests(remaining);
+            }
+            match authenticate(
+                &*context.secrets.read().await,
+                &context.token_signer,
+                &token,
+            )
+            .and_then(|secret| secret.allowed_from(ip))
+            {
+                Some(secret) => Some(secret),
+                None => {
+                    println!("WS: `{user_agent}` rejected, invalid token.");
+                    if let Some(lockout) = context.lockouts.record_failure(ip) {
+                        return too_many_requests(lockout);
+                    }
+                    return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+                }
+            }
+        }
+        // Without a token, the client certificate may authenticate it.
+        None => match client_cert {
+            Some(Extension(cert)) => context
+                .secrets
+                .read()
+                .await
+                .by_client_subject(&cert.subject)
+                .cloned()
+                .and_then(|secret| secret.allowed_from(ip)),
+            None => None,
+        },
+    };
+
+    let mut keys = query.keys.as_deref().map(split_keys).unwrap_or_default();
+    if let Some(Path(key)) = key {
+        keys.push(key);
+    }
+
     println!("WS: `{user_agent}` at connected.");
-    ws.on_upgrade(move |socket| handle_websocket(socket, context))
+    let connection = Connection {
+        remote_addr,
+        client_ip: ip,
+        user_agent,
+        secret,
+        keys,
+        mode: query.mode,
+    };
+    ws.protocols(Encoding::PROTOCOLS)
+        .on_upgrade(move |socket| handle_websocket(socket, context, connection))
+}
+
+/// Split a comma separated list of keys, ignoring empty ones.
+fn split_keys(keys: &str) -> Vec<String> {
+    keys.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Find the token in the `Sec-WebSocket-Protocol` header, offered as `bearer.<token>`.
+fn protocol_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(PROTOCOL_TOKEN_PREFIX))
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted_proxies: &[&str]) -> Config {
+        Config {
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|proxy| proxy.parse().unwrap())
+                .collect(),
+            ..Config::default()
+        }
+    }
+
+    fn headers(forwarded: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("X-Forwarded-For", value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn client_ip_without_trusted_proxies() {
+        let config = config(&[]);
+        let forwarded = headers(&["203.0.113.7"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("127.0.0.1")
+        );
+        assert_eq!(
+            client_ip(&config, addr("[::ffff:10.0.0.1]:1234"), &forwarded),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn client_ip_ignores_untrusted_peer() {
+        let config = config(&["10.0.0.0/8"]);
+        let forwarded = headers(&["203.0.113.7"]);
+        assert_eq!(
+            client_ip(&config, addr("192.0.2.1:1234"), &forwarded),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn client_ip_behind_trusted_proxy() {
+        let config = config(&["127.0.0.1"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &headers(&["203.0.113.7"])),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &headers(&[])),
+            ip("127.0.0.1")
+        );
+        assert_eq!(
+            client_ip(
+                &config,
+                addr("[::ffff:127.0.0.1]:1234"),
+                &headers(&["::ffff:203.0.113.7"])
+            ),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn client_ip_takes_rightmost_untrusted() {
+        let config = config(&["127.0.0.1", "10.0.0.0/8"]);
+        // The client can put anything on the left, only the addresses added by the proxies count.
+        let forwarded = headers(&["198.51.100.1, 203.0.113.7, 10.0.0.2"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("203.0.113.7")
+        );
+        // Every proxy may add its own header.
+        let forwarded = headers(&["198.51.100.1", "203.0.113.7", "10.0.0.2"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn client_ip_only_trusted_hops() {
+        let config = config(&["127.0.0.1", "10.0.0.0/8"]);
+        let forwarded = headers(&["10.0.0.3, 10.0.0.2"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn client_ip_stops_at_invalid_address() {
+        let config = config(&["127.0.0.1", "10.0.0.0/8"]);
+        let forwarded = headers(&["203.0.113.7, unknown, 10.0.0.2"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("10.0.0.2")
+        );
+        let forwarded = headers(&["203.0.113.7, unknown"]);
+        assert_eq!(
+            client_ip(&config, addr("127.0.0.1:1234"), &forwarded),
+            ip("127.0.0.1")
+        );
+    }
 }
@@ -1,73 +1,179 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 
+use crate::changes::{unix_millis, Change, Event, Operation, Publication};
+use crate::config::Secret;
+use crate::workers::audit::AuditRecord;
+
+/// A stored value and the sequence number of the change that wrote it.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: serde_json::Value,
+    /// The `seq` of the last change to this key. The `seq` at startup if loaded from disk, `0` if missing.
+    pub revision: u64,
+}
+
+/// Who makes a write. It's recorded with the change and in the audit log.
+#[derive(Debug, Clone)]
+pub struct Author {
+    /// The name of the secret.
+    pub name: String,
+    /// The address of the client, which is the forwarded one behind a trusted proxy.
+    pub client_ip: Option<IpAddr>,
+}
+
+impl Author {
+    pub fn new(secret: &Secret, client_ip: IpAddr) -> Self {
+        Self {
+            name: secret.name.clone(),
+            client_ip: Some(client_ip),
+        }
+    }
+
+    /// Edits of the data files on disk.
+    pub fn file() -> Self {
+        Self {
+            name: "file".to_owned(),
+            client_ip: None,
+        }
+    }
+}
+
 pub struct KeyService {
     // cloned from app context.
-    pub hashmap: Arc<RwLock<std::collections::HashMap<String, serde_json::Value>>>,
-    pub sender_file_save: mpsc::Sender<(String, serde_json::Value)>,
-    pub broadcaster: mpsc::Sender<(String, serde_json::Value)>,
+    pub hashmap: Arc<RwLock<HashMap<String, Entry>>>,
+    pub sender_file_save: mpsc::Sender<(String, Option<serde_json::Value>)>,
+    pub broadcaster: mpsc::Sender<Event>,
+    pub sender_audit: mpsc::Sender<AuditRecord>,
+    /// The sequence number of the latest change.
+    pub seq: AtomicU64,
 }
 
 pub trait KeyServiceTrait {
     /// Get a key from the hashmap
     async fn get_key(&self, key: &str) -> Result<serde_json::Value, KeyServiceError>;
-    /// Post a key to the hashmap
-    async fn post_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError>;
+    /// Get a key and its revision from the hashmap
+    async fn get_entry(&self, key: &str) -> Result<Entry, KeyServiceError>;
+    /// Post a key to the hashmap, returns the new revision.
+    async fn post_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError>;
     /// Put a key to the hashmap
-    /// It's same as `post_key`
-    async fn put_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError>;
+    /// It's same as `post_key`, but recorded as `Operation::Put`.
+    async fn put_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError>;
     /// Patch a key to the hashmap
     /// It uses RFC-6902 for modifying the value.
-    async fn patch_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError>;
+    async fn patch_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError>;
+    /// Delete a key from the hashmap and its file, returns the revision of the deletion.
+    async fn delete_key(&self, key: &str, author: &Author) -> Result<u64, KeyServiceError>;
     async fn list_keys(&self) -> Result<Vec<String>, KeyServiceError>;
+    /// Publish a value to the subscribers of an ephemeral channel
+    /// It's neither stored in the hashmap nor saved to the file.
+    async fn publish(&self, channel: &str, value: serde_json::Value) -> Result<(), KeyServiceError>;
 }
 
 impl KeyServiceTrait for KeyService {
     async fn get_key(&self, key: &str) -> Result<serde_json::Value, KeyServiceError> {
+        Ok(self.get_entry(key).await?.value)
+    }
+
+    async fn get_entry(&self, key: &str) -> Result<Entry, KeyServiceError> {
         {
             let hashmap = self.hashmap.read().await;
-            if let Some(value) = hashmap.get(key) {
-                return Ok(value.clone());
+            if let Some(entry) = hashmap.get(key) {
+                return Ok(entry.clone());
             }
         }
-        Ok(serde_json::Value::Null)
+        Ok(Entry {
+            value: serde_json::Value::Null,
+            revision: 0,
+        })
     }
 
-    async fn post_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError> {
-        {
-            let mut hashmap = self.hashmap.write().await;
-            hashmap.insert(key.to_owned(), value.clone());
-        }
-        // Sends to the file_save channel in order to save the data to the file.
-        self.sender_file_save
-            .send((key.to_owned(), value.clone()))
-            .await
-            .unwrap();
-        // Sends to the broadcaster channel in order to broadcast the data to the clients.
-        self.broadcaster
-            .send((key.to_owned(), value.clone()))
-            .await
-            .unwrap();
-        Ok(())
+    async fn post_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError> {
+        let mut hashmap = self.hashmap.write().await;
+        Ok(self
+            .commit(
+                &mut hashmap,
+                key,
+                author,
+                Operation::Post,
+                Some(value),
+                None,
+            )
+            .await)
     }
 
-    async fn put_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError> {
-        self.post_key(key, value).await
+    async fn put_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError> {
+        let mut hashmap = self.hashmap.write().await;
+        Ok(self
+            .commit(&mut hashmap, key, author, Operation::Put, Some(value), None)
+            .await)
     }
 
-    async fn patch_key(&self, key: &str, value: serde_json::Value) -> Result<(), KeyServiceError> {
+    async fn patch_key(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+        author: &Author,
+    ) -> Result<u64, KeyServiceError> {
         // Parse the json-patch on value parameter first.
         let patch_data: json_patch::Patch =
-            serde_json::from_value(value).map_err(KeyServiceError::UnableToParsePatch)?;
-        let mut data = {
-            let hashmap = self.hashmap.write().await;
-            hashmap
-                .get(key)
-                .ok_or(KeyServiceError::KeyNotFound)?
-                .clone()
-        };
+            serde_json::from_value(value.clone()).map_err(KeyServiceError::UnableToParsePatch)?;
+        // Hold the lock until committed, so concurrent patches don't overwrite each other.
+        let mut hashmap = self.hashmap.write().await;
+        let mut data = hashmap
+            .get(key)
+            .ok_or(KeyServiceError::KeyNotFound)?
+            .value
+            .clone();
         json_patch::patch(&mut data, &patch_data).map_err(KeyServiceError::UnableToPatch)?;
-        self.put_key(key, data).await
+        Ok(self
+            .commit(
+                &mut hashmap,
+                key,
+                author,
+                Operation::Patch,
+                Some(data),
+                Some(value),
+            )
+            .await)
+    }
+
+    async fn delete_key(&self, key: &str, author: &Author) -> Result<u64, KeyServiceError> {
+        let mut hashmap = self.hashmap.write().await;
+        if !hashmap.contains_key(key) {
+            return Err(KeyServiceError::KeyNotFound);
+        }
+        Ok(self
+            .commit(&mut hashmap, key, author, Operation::Delete, None, None)
+            .await)
     }
 
     async fn list_keys(&self) -> Result<Vec<String>, KeyServiceError> {
@@ -77,6 +183,81 @@ impl KeyServiceTrait for KeyService {
         };
         Ok(list)
     }
+
+    async fn publish(&self, channel: &str, value: serde_json::Value) -> Result<(), KeyServiceError> {
+        self.broadcaster
+            .send(Event::Publish(Publication {
+                channel: channel.to_owned(),
+                value,
+            }))
+            .await
+            .unwrap();
+        Ok(())
+    }
+}
+
+impl KeyService {
+    /// Store the value with the next sequence number, then notify the workers and audit it.
+    /// `None` deletes the key.
+    /// The caller holds the write lock, so changes reach the broadcaster in `seq` order.
+    async fn commit(
+        &self,
+        hashmap: &mut HashMap<String, Entry>,
+        key: &str,
+        author: &Author,
+        operation: Operation,
+        value: Option<serde_json::Value>,
+        patch: Option<serde_json::Value>,
+    ) -> u64 {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let timestamp = unix_millis();
+        let before_revision = hashmap.get(key).map(|entry| entry.revision);
+        match &value {
+            Some(value) => {
+                hashmap.insert(
+                    key.to_owned(),
+                    Entry {
+                        value: value.clone(),
+                        revision: seq,
+                    },
+                );
+            }
+            None => {
+                hashmap.remove(key);
+            }
+        }
+        // Sends to the file_save channel in order to save the data to the file.
+        self.sender_file_save
+            .send((key.to_owned(), value.clone()))
+            .await
+            .unwrap();
+        // Sends to the broadcaster channel in order to broadcast the data to the clients.
+        self.broadcaster
+            .send(Event::Change(Change {
+                seq,
+                key: key.to_owned(),
+                operation,
+                timestamp,
+                author: Some(author.name.clone()),
+                value,
+                patch,
+            }))
+            .await
+            .unwrap();
+        self.sender_audit
+            .send(AuditRecord {
+                time: timestamp,
+                name: author.name.clone(),
+                key: key.to_owned(),
+                operation,
+                remote_addr: author.client_ip.map(|ip| ip.to_string()),
+                before_revision,
+                after_revision: seq,
+            })
+            .await
+            .unwrap();
+        seq
+    }
 }
 
 #[derive(Debug)]
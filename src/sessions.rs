@@ -0,0 +1,244 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::extract::ws::{CloseFrame, Message};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::changes::unix_millis;
+use crate::config::{Permission, Secret, Secrets};
+
+/// Close code sent when the session is disconnected by an admin.
+const CLOSE_KICKED: u16 = 4010;
+
+/// A live websocket session.
+pub struct Session {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    /// The address of the client, which differs from `remote_addr` behind a trusted proxy.
+    pub client_ip: IpAddr,
+    pub user_agent: String,
+    /// Unix time in milliseconds.
+    pub connected_at: u64,
+    /// The secret used to authenticate, `None` until authenticated.
+    pub secret: RwLock<Option<Secret>>,
+    pub subscriptions: Mutex<BTreeSet<String>>,
+    pub messages_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    /// Control frames of the connection, used to close it.
+    control: mpsc::Sender<Message>,
+    /// Ends the connection without the close handshake.
+    pub kicked: Notify,
+    /// Sends the close frame ahead of the pending messages.
+    pub closing: Notify,
+}
+
+/// The serialized view of a `Session`.
+#[derive(Serialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub name: Option<String>,
+    pub remote_addr: String,
+    /// The address of the client, which differs from `remote_addr` behind a trusted proxy.
+    pub client_ip: String,
+    pub user_agent: String,
+    pub connected_at: u64,
+    pub subscriptions: Vec<String>,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+}
+
+impl Session {
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            name: self
+                .secret
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(|secret| secret.name.clone()),
+            remote_addr: self.remote_addr.to_string(),
+            client_ip: self.client_ip.to_string(),
+            user_agent: self.user_agent.clone(),
+            connected_at: self.connected_at,
+            subscriptions: self.subscriptions.lock().unwrap().iter().cloned().collect(),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drop the secret and close the connection, or drop it if the close frame can't be queued.
+    pub fn kick(&self, reason: &'static str) {
+        *self.secret.write().unwrap() = None;
+        self.close(reason);
+    }
+
+    fn close(&self, reason: &'static str) {
+        let frame = Message::Close(Some(CloseFrame {
+            code: CLOSE_KICKED,
+            reason: reason.into(),
+        }));
+        if self.control.try_send(frame).is_err() {
+            self.kicked.notify_one();
+        } else {
+            self.closing.notify_one();
+        }
+    }
+}
+
+/// The registry of live websocket sessions.
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: RwLock<HashMap<u64, Arc<Session>>>,
+    /// Bumped whenever the presence changes: connect, disconnect, authenticate or subscribe.
+    changed: watch::Sender<u64>,
+    /// Held while counting the sessions of a secret, so concurrent sessions can't both take the last slot.
+    authenticating: Mutex<()>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            sessions: RwLock::new(HashMap::new()),
+            changed: watch::channel(0).0,
+            authenticating: Mutex::new(()),
+        }
+    }
+}
+
+impl Sessions {
+    pub fn register(
+        &self,
+        remote_addr: SocketAddr,
+        client_ip: IpAddr,
+        user_agent: String,
+        control: mpsc::Sender<Message>,
+    ) -> Arc<Session> {
+        let connected_at = unix_millis();
+        let session = Arc::new(Session {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            remote_addr,
+            client_ip,
+            user_agent,
+            connected_at,
+            secret: RwLock::new(None),
+            subscriptions: Mutex::new(BTreeSet::new()),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            control,
+            kicked: Notify::new(),
+            closing: Notify::new(),
+        });
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id, session.clone());
+        self.notify_changed();
+        session
+    }
+
+    /// Authenticate the session with the secret, unless `max_per_secret` other sessions
+    /// already use a secret of the same name. `0` is unlimited.
+    pub fn authenticate(&self, session: &Session, secret: Secret, max_per_secret: usize) -> bool {
+        let _authenticating = self.authenticating.lock().unwrap();
+        let sessions = self.sessions.read().unwrap();
+        if max_per_secret > 0 {
+            let count = sessions
+                .values()
+                .filter(|other| other.id != session.id)
+                .filter(|other| {
+                    other
+                        .secret
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .is_some_and(|other| other.name == secret.name)
+                })
+                .count();
+            if count >= max_per_secret {
+                return false;
+            }
+        }
+        *session.secret.write().unwrap() = Some(secret);
+        true
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.sessions.write().unwrap().remove(&id);
+        self.notify_changed();
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Session>> {
+        self.sessions.read().unwrap().get(&id).cloned()
+    }
+
+    /// Every session, ordered by id.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut list: Vec<SessionInfo> = self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .map(|session| session.info())
+            .collect();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Apply reloaded secrets to the authenticated sessions. \
+    /// Sessions whose secret was revoked, or which are subscribed to keys it no longer allows,
+    /// are disconnected. The others get the new permissions.
+    pub fn apply_secrets(&self, secrets: &Secrets) {
+        for session in self.sessions.read().unwrap().values() {
+            let mut secret = session.secret.write().unwrap();
+            let Some(old) = secret.as_ref() else {
+                continue;
+            };
+            let Some(new) = secrets.current(old).cloned() else {
+                println!("WS: session {} disconnected, secret revoked.", session.id);
+                *secret = None;
+                session.close("secret revoked");
+                continue;
+            };
+            if !new.allows_ip(session.client_ip) {
+                println!(
+                    "WS: session {} disconnected, address not allowed.",
+                    session.id
+                );
+                *secret = None;
+                session.close("address not allowed");
+                continue;
+            }
+            let allowed = session
+                .subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|key| new.allows(Permission::Read, key));
+            if !allowed {
+                println!(
+                    "WS: session {} disconnected, permissions changed.",
+                    session.id
+                );
+                *secret = None;
+                session.close("permissions changed");
+                continue;
+            }
+            *secret = Some(new);
+        }
+        self.notify_changed();
+    }
+
+    pub fn notify_changed(&self) {
+        self.changed.send_modify(|version| *version += 1);
+    }
+
+    /// Receive a notification whenever the presence changes.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changed.subscribe()
+    }
+}
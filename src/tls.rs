@@ -0,0 +1,142 @@
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::ServiceExt;
+
+use crate::config::Config;
+
+/// The verified client certificate of a TLS connection, added to its requests.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// e.g. `CN=overlay, O=Venue`
+    pub subject: String,
+}
+
+/// The TLS settings of new connections, swapped when the certificate changes.
+pub type SharedTlsConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+/// Load the certificate, its key and the client CA from the paths in the config.
+pub fn load_tls_config(config: &Config) -> Result<ServerConfig, String> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) else {
+        return Err("both JSONKV_TLS_CERT and JSONKV_TLS_KEY are required".to_owned());
+    };
+    let certs = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match &config.tls_client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_path)? {
+                roots.add(cert).map_err(|err| format!("{ca_path}: {err}"))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.tls_client_cert_required {
+                verifier
+            } else {
+                // Clients without a certificate can still use a token.
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|err| err.to_string())?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(tls_config)
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|err| format!("{path}: {err}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("{path}: {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("{path}: no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|err| format!("{path}: {err}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("{path}: {err}"))?
+        .ok_or_else(|| format!("{path}: no private key found"))
+}
+
+/// Serve the router over TLS, like `axum::serve` with `ConnectInfo<SocketAddr>`.
+/// The subject of a verified client certificate is added to the requests as `ClientCert`.
+pub async fn serve_tls(listener: TcpListener, router: Router, tls_config: SharedTlsConfig) {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(tls_config.read().unwrap().clone());
+        let router = router.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("TLS handshake with {remote_addr} failed: {e}");
+                    return;
+                }
+            };
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(client_cert);
+            if let Some(cert) = &client_cert {
+                println!("TLS client {remote_addr} presented `{}`", cert.subject);
+            }
+
+            let service = tower::service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                if let Some(cert) = &client_cert {
+                    request.extensions_mut().insert(cert.clone());
+                }
+                router.clone().oneshot(request)
+            });
+            let _ = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    TowerToHyperService::new(service),
+                )
+                .await;
+        });
+    }
+}
+
+fn client_cert(cert: &CertificateDer) -> Option<ClientCert> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(ClientCert {
+        subject: cert.subject().to_string(),
+    })
+}
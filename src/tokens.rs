@@ -0,0 +1,165 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::changes::unix_millis;
+use crate::config::{Permission, Secret, Secrets};
+
+/// The prefix of a signed token, `jkv1.<claims>.<signature>`.
+pub const TOKEN_PREFIX: &str = "jkv1.";
+/// Prefixed to the names of signed tokens, so they can't pass for a static secret.
+pub const TOKEN_NAME_PREFIX: &str = "token:";
+
+/// What a signed token grants.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+    pub name: String,
+    /// Unix time in milliseconds after which the token is no longer accepted.
+    pub exp: u64,
+    pub keys: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+/// Signs and verifies short-lived tokens with HMAC-SHA256.
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+
+    pub fn sign(&self, claims: &Claims) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&claims).finalize().into_bytes());
+        format!("{TOKEN_PREFIX}{claims}.{signature}")
+    }
+
+    /// Get the claims of the token, if it's signed by us and not expired.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (claims, signature) = token.strip_prefix(TOKEN_PREFIX)?.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        // Compared in constant time.
+        self.mac(claims).verify_slice(&signature).ok()?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        if claims.exp <= unix_millis() {
+            return None;
+        }
+        Some(claims)
+    }
+
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+/// Find the secret of a static or signed token.
+pub fn authenticate(secrets: &Secrets, signer: &TokenSigner, token: &str) -> Option<Secret> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return secrets.get(token).cloned();
+    }
+    let claims = signer.verify(token)?;
+    Some(Secret {
+        // Kept so `Secret::is_signed` can tell it apart from the static secrets.
+        secret: token.to_owned(),
+        name: format!("{TOKEN_NAME_PREFIX}{}", claims.name),
+        description: None,
+        permissions: claims.permissions,
+        keys: claims.keys,
+        expires_at: Some(claims.exp),
+        client_subject: None,
+        allowed_ips: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            name: "overlay".to_owned(),
+            exp,
+            keys: vec!["score*".to_owned()],
+            permissions: vec![Permission::Read],
+        }
+    }
+
+    #[test]
+    fn verify_accepts_signed_token() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        let token = signer.sign(&claims(unix_millis() + 60_000));
+        let verified = signer.verify(&token).unwrap();
+        assert_eq!(verified.name, "overlay");
+        assert_eq!(verified.keys, ["score*"]);
+        assert_eq!(verified.permissions, [Permission::Read]);
+    }
+
+    #[test]
+    fn verify_rejects_expired_token() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        assert!(signer.verify(&signer.sign(&claims(unix_millis() - 1))).is_none());
+        assert!(signer.verify(&signer.sign(&claims(0))).is_none());
+    }
+
+    #[test]
+    fn verify_rejects_other_key() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        let other = TokenSigner::new(b"other key".to_vec());
+        let token = other.sign(&claims(unix_millis() + 60_000));
+        assert!(signer.verify(&token).is_none());
+    }
+
+    #[test]
+    fn verify_rejects_tampered_token() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        let token = signer.sign(&claims(unix_millis() + 60_000));
+        let (claims_part, signature) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .unwrap()
+            .split_once('.')
+            .unwrap();
+
+        // Claims granting more, with the original signature.
+        let mut granted = claims(unix_millis() + 60_000);
+        granted.keys = vec!["*".to_owned()];
+        granted.permissions = vec![Permission::Read, Permission::Write];
+        let granted = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&granted).unwrap());
+        assert!(signer
+            .verify(&format!("{TOKEN_PREFIX}{granted}.{signature}"))
+            .is_none());
+
+        // A flipped signature.
+        let mut flipped = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        flipped[0] ^= 1;
+        let flipped = URL_SAFE_NO_PAD.encode(flipped);
+        assert!(signer
+            .verify(&format!("{TOKEN_PREFIX}{claims_part}.{flipped}"))
+            .is_none());
+    }
+
+    #[test]
+    fn verify_rejects_malformed_token() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        let token = signer.sign(&claims(unix_millis() + 60_000));
+        assert!(signer.verify(token.strip_prefix(TOKEN_PREFIX).unwrap()).is_none());
+        assert!(signer.verify(&token[..token.len() - 2]).is_none());
+        assert!(signer.verify(TOKEN_PREFIX).is_none());
+        assert!(signer.verify("jkv1.a.b").is_none());
+        assert!(signer.verify("").is_none());
+    }
+
+    #[test]
+    fn authenticate_namespaces_token_names() {
+        let signer = TokenSigner::new(b"key".to_vec());
+        let token = signer.sign(&claims(unix_millis() + 60_000));
+        let secrets = Secrets { secret: Vec::new() };
+        let secret = authenticate(&secrets, &signer, &token).unwrap();
+        assert_eq!(secret.name, "token:overlay");
+        assert!(secret.is_signed());
+    }
+}
@@ -1,49 +1,328 @@
 //
 use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     ops::ControlFlow,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex as StdMutex, RwLock,
+    },
 };
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use futures::{stream::StreamExt, SinkExt};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, Mutex},
+    task::JoinHandle,
+    time::{timeout_at, Duration, Instant},
+};
+
+use crate::{
+    changes::{wait_until, Change, Event},
+    codec::{CodecError, Encoding},
+    config::{Permission, Secret},
+    context::AppContext,
+    outbox::{Coalesce, Outbox},
+    service::{Author, KeyServiceError, KeyServiceTrait},
+    sessions::{Session, SessionInfo},
+    tokens::authenticate,
+};
+
+/// The prefix of a subprotocol carrying the token, e.g. `bearer.<token>`.
+pub const PROTOCOL_TOKEN_PREFIX: &str = "bearer.";
+
+/// Close code sent when the client fails to authenticate.
+const CLOSE_UNAUTHORIZED: u16 = 4001;
+/// Close code sent when the client did not authenticate in time.
+const CLOSE_AUTH_TIMEOUT: u16 = 4008;
+/// Close code sent when the client doesn't read its messages.
+const CLOSE_TOO_SLOW: u16 = 4009;
+/// Close code sent when the secret already has too many connections.
+const CLOSE_TOO_MANY_CONNECTIONS: u16 = 4013;
+/// Close code sent when the remote IP is locked out after failing to authenticate too often.
+const CLOSE_LOCKED_OUT: u16 = 4029;
+
+/// The maximum number of pending messages, not counting the coalesced key updates.
+const OUTBOX_CAPACITY: usize = 512;
+/// The lowest `max_rate`, one update every 1000 seconds. Lower rates would overflow the throttle interval.
+const MIN_RATE: f64 = 0.001;
+
+/// Where the websocket connection comes from.
+pub struct Connection {
+    pub remote_addr: SocketAddr,
+    /// The address of the client, which differs from `remote_addr` behind a trusted proxy.
+    pub client_ip: IpAddr,
+    pub user_agent: String,
+    /// The secret, if the token was already verified at upgrade time.
+    pub secret: Option<Secret>,
+    /// The keys to subscribe to once authenticated.
+    pub keys: Vec<String>,
+    pub mode: OutputMode,
+}
 
-use crate::{context::AppContext, service::KeyServiceTrait};
+/// What is sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// Every `ServerMessage`.
+    #[default]
+    Envelope,
+    /// Only the raw values of the subscribed keys, one per frame. `null` if deleted.
+    Values,
+}
 
 pub struct ListenerContext {
-    authorized: RwLock<bool>,
-    listening: RwLock<Vec<String>>,
-    sender: mpsc::Sender<ServerMessage>,
+    /// The subscribed keys and the `seq` of the last change sent for each of them.
+    listening: Mutex<HashMap<String, u64>>,
+    /// The subscribed ephemeral channels.
+    channels: StdMutex<HashSet<String>>,
+    outbox: Outbox<OutboxKey, ServerMessage>,
+    /// Control frames (ping, close) sent after the pending messages.
+    control: mpsc::Sender<Message>,
+    /// The last time any frame was received from the client.
+    last_seen: StdMutex<Instant>,
+    /// Whether the client missed the last heartbeat.
+    idle: AtomicBool,
+    /// The encoding of outgoing messages, negotiated via the subprotocol or `Authenticate`.
+    encoding: RwLock<Encoding>,
+    /// This connection in the session registry.
+    session: Arc<Session>,
+    /// Sends the presence updates, while subscribed to them.
+    presence_task: StdMutex<Option<JoinHandle<()>>>,
+    /// The keys to subscribe to once authenticated.
+    pending_keys: StdMutex<Vec<String>>,
+    mode: OutputMode,
+    /// The secret used until authenticated, which may only read the public keys.
+    anonymous: Option<Secret>,
+    /// The failed `Authenticate` messages.
+    auth_failures: AtomicU32,
 }
-pub async fn handle_websocket(mut socket: WebSocket, context: Arc<AppContext>) {
+
+impl ListenerContext {
+    fn is_authorized(&self) -> bool {
+        self.session.secret.read().unwrap().is_some()
+    }
+
+    fn has_permission(&self, permission: Permission) -> bool {
+        match &*self.session.secret.read().unwrap() {
+            Some(secret) => secret.has_permission(permission),
+            None => self
+                .anonymous
+                .as_ref()
+                .is_some_and(|secret| secret.has_permission(permission)),
+        }
+    }
+
+    /// The author of the writes made by this connection.
+    fn author(&self) -> Author {
+        let name = match &*self.session.secret.read().unwrap() {
+            Some(secret) => secret.name.clone(),
+            None => self
+                .anonymous
+                .as_ref()
+                .map_or_else(String::new, |secret| secret.name.clone()),
+        };
+        Author {
+            name,
+            client_ip: Some(self.session.client_ip),
+        }
+    }
+
+    /// Whether the secret has the permission on the key.
+    fn allows(&self, permission: Permission, key: &str) -> bool {
+        match &*self.session.secret.read().unwrap() {
+            Some(secret) => secret.allows(permission, key),
+            None => self
+                .anonymous
+                .as_ref()
+                .is_some_and(|secret| secret.allows(permission, key)),
+        }
+    }
+
+    /// Queue a message in order. Closes the websocket if the client stopped reading.
+    fn send(&self, message: ServerMessage) {
+        if !self.outbox.push(message) {
+            println!("client outbox is full");
+            self.close(CLOSE_TOO_SLOW, "too many pending messages");
+        }
+    }
+
+    /// Queue a message of the key, replacing any pending one.
+    fn send_latest(&self, key: OutboxKey, message: ServerMessage) {
+        self.outbox.push_latest(key, message);
+    }
+
+    /// Queue a change of a subscribed key as `Data`, or `Deleted` if the key was deleted.
+    fn send_change(&self, change: Change) {
+        let key = OutboxKey::Data(change.key.clone());
+        self.send_latest(key, change_message(change));
+    }
+
+    /// Encode the message for the client, or `None` if the output mode skips it.
+    fn encode(&self, message: &ServerMessage) -> Option<Result<Message, CodecError>> {
+        let encoding = *self.encoding.read().unwrap();
+        match self.mode {
+            OutputMode::Envelope => Some(encoding.encode(message)),
+            OutputMode::Values => match message {
+                ServerMessage::Subscribed { value, .. } | ServerMessage::Data { value, .. } => {
+                    Some(encoding.encode(value))
+                }
+                ServerMessage::Deleted { .. } => Some(encoding.encode(&serde_json::Value::Null)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Close the websocket after the pending messages are sent.
+    /// It never waits, since it may be called with the change log locked.
+    fn close(&self, code: u16, reason: &'static str) {
+        self.outbox.close();
+        let frame = Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+        if self.control.try_send(frame).is_err() {
+            // The control channel is full of pings, end the connection without the close handshake.
+            self.session.kicked.notify_one();
+        }
+    }
+}
+
+/// Close a connection exceeding the connection limit of its secret.
+fn reject_too_many_connections(context: &ListenerContext, app_context: &AppContext) {
+    println!("client rejected, too many connections for the secret");
+    app_context
+        .metrics
+        .ws_connections_rejected
+        .fetch_add(1, Ordering::Relaxed);
+    context.close(CLOSE_TOO_MANY_CONNECTIONS, "too many connections");
+}
+
+/// Handle the upgraded websocket.
+pub async fn handle_websocket(
+    mut socket: WebSocket,
+    context: Arc<AppContext>,
+    connection: Connection,
+) {
     if socket.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
         println!("Could not send ping!");
         return;
     }
 
+    let encoding = socket
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Encoding::from_protocol)
+        .unwrap_or_default();
     let (mut sender, mut receiver) = socket.split();
 
     // send the first message, auth.
-    let serialized =
-        serde_json::to_string(&ServerMessage::Auth("jsonkv-server".to_string())).unwrap();
-    if sender.send(Message::Text(serialized)).await.is_err() {
-        println!("client abruptly disconnected");
-        return;
+    if connection.mode == OutputMode::Envelope {
+        let serialized = encoding
+            .encode(&ServerMessage::Auth("jsonkv-server".to_string()))
+            .unwrap();
+        if sender.send(serialized).await.is_err() {
+            println!("client abruptly disconnected");
+            return;
+        }
     }
 
-    let (sender_channel_tx, mut sender_channel_rx) = mpsc::channel(512);
+    // Subscribe before any snapshot is taken, so no change in between is missed.
+    let mut broadcast_receiver = context.broadcast.subscribe();
+
+    let (control_tx, mut control_rx) = mpsc::channel(4);
+    let session = context.sessions.register(
+        connection.remote_addr,
+        connection.client_ip,
+        connection.user_agent,
+        control_tx.clone(),
+    );
+    let mut too_many_connections = false;
+    let authorized = match connection.secret {
+        Some(secret) => {
+            // Hold the secrets while authenticating, so a reload revoking the secret can't be missed.
+            let secrets = context.secrets.read().await;
+            match secrets.current(&secret).cloned() {
+                Some(secret) if secret.allows_ip(session.client_ip) => {
+                    let max = context.config.ws_max_connections_per_secret;
+                    too_many_connections = !context.sessions.authenticate(&session, secret, max);
+                    !too_many_connections
+                }
+                _ => false,
+            }
+        }
+        None => false,
+    };
     let listener_context = Arc::new(ListenerContext {
-        authorized: RwLock::new(false),
-        listening: RwLock::new(Vec::new()),
-        sender: sender_channel_tx,
+        listening: Mutex::new(HashMap::new()),
+        channels: StdMutex::new(HashSet::new()),
+        outbox: Outbox::new(OUTBOX_CAPACITY),
+        control: control_tx,
+        last_seen: StdMutex::new(Instant::now()),
+        idle: AtomicBool::new(false),
+        encoding: RwLock::new(encoding),
+        session: session.clone(),
+        presence_task: StdMutex::new(None),
+        pending_keys: StdMutex::new(connection.keys),
+        mode: connection.mode,
+        anonymous: context.config.anonymous_secret(),
+        auth_failures: AtomicU32::new(0),
     });
+    context.metrics.ws_connected.fetch_add(1, Ordering::Relaxed);
+    if too_many_connections {
+        reject_too_many_connections(&listener_context, &context);
+    } else if authorized {
+        on_authenticated(&listener_context, &context).await;
+    } else {
+        // Public keys don't wait for the authentication.
+        let public = {
+            let keys = listener_context.pending_keys.lock().unwrap();
+            !keys.is_empty()
+                && keys
+                    .iter()
+                    .all(|key| listener_context.allows(Permission::Read, key))
+        };
+        if public {
+            subscribe_pending(&listener_context, &context).await;
+        }
+    }
 
     // Receive task will receive messages from the websocket and process them
     let cloned = context.clone();
     let listener_cloned = listener_context.clone();
+    let auth_deadline = Instant::now() + Duration::from_millis(context.config.ws_auth_timeout);
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
+        let mut deadline_passed = false;
+        loop {
+            let msg = if deadline_passed || listener_cloned.is_authorized() {
+                receiver.next().await
+            } else {
+                match timeout_at(auth_deadline, receiver.next()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        deadline_passed = true;
+                        // Anonymous clients may stay to read the public keys they subscribed to.
+                        let subscribed = !listener_cloned.listening.lock().await.is_empty()
+                            || !listener_cloned.channels.lock().unwrap().is_empty();
+                        if subscribed {
+                            continue;
+                        }
+                        // send_task ends the connection once the close frame is sent.
+                        println!("client did not authenticate in time");
+                        listener_cloned.close(CLOSE_AUTH_TIMEOUT, "authentication timeout");
+                        continue;
+                    }
+                }
+            };
+            let Some(Ok(msg)) = msg else {
+                break;
+            };
+            *listener_cloned.last_seen.lock().unwrap() = Instant::now();
+            listener_cloned
+                .session
+                .messages_received
+                .fetch_add(1, Ordering::Relaxed);
             if process_message(&listener_cloned, &cloned, msg)
                 .await
                 .is_break()
@@ -54,47 +333,187 @@ pub async fn handle_websocket(mut socket: WebSocket, context: Arc<AppContext>) {
     });
 
     // Send task will send messages to the websocket
+    let listener_cloned = listener_context.clone();
     let mut send_task = tokio::spawn(async move {
         // listen
-        while let Some(i) = sender_channel_rx.recv().await {
-            let serialized = serde_json::to_string(&i).unwrap();
-            if sender.send(Message::Text(serialized)).await.is_err() {
-                println!("client abruptly disconnected");
+        loop {
+            tokio::select! {
+                // Flush the pending messages before closing, unless kicked.
+                biased;
+                _ = listener_cloned.session.closing.notified() => {
+                    // The close frame is already queued, so it ends the loop.
+                    while let Some(frame) = control_rx.recv().await {
+                        let is_close = matches!(frame, Message::Close(_));
+                        if sender.send(frame).await.is_err() || is_close {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                Some(i) = listener_cloned.outbox.recv() => {
+                    let serialized = match listener_cloned.encode(&i) {
+                        Some(Ok(serialized)) => serialized,
+                        None => continue,
+                        Some(Err(err)) => {
+                            println!("failed to encode message: {err}");
+                            continue;
+                        }
+                    };
+                    if sender.send(serialized).await.is_err() {
+                        println!("client abruptly disconnected");
+                        break;
+                    }
+                    listener_cloned
+                        .session
+                        .messages_sent
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Some(frame) = control_rx.recv() => {
+                    let is_close = matches!(frame, Message::Close(_));
+                    if sender.send(frame).await.is_err() || is_close {
+                        break;
+                    }
+                }
+                else => break,
+            }
+        }
+    });
+
+    // Heartbeat task pings the client, and ends the connection if it stops responding.
+    let cloned = context.clone();
+    let listener_cloned = listener_context.clone();
+    let mut heartbeat_task = tokio::spawn(async move {
+        let ping_interval = Duration::from_millis(cloned.config.ws_ping_interval);
+        let ping_timeout = Duration::from_millis(cloned.config.ws_ping_timeout);
+        let mut interval = tokio::time::interval(ping_interval);
+        interval.tick().await; // The first tick completes immediately.
+        let mut expired = false;
+        loop {
+            // The secret may change when authenticating or on reload, so it's read again on every tick.
+            let expires_at = listener_cloned
+                .session
+                .secret
+                .read()
+                .unwrap()
+                .as_ref()
+                .and_then(|secret| secret.expires_at)
+                .filter(|_| !expired);
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = wait_until(expires_at) => {
+                    println!("WS: session {} disconnected, secret expired.", listener_cloned.session.id);
+                    listener_cloned.session.kick("secret expired");
+                    expired = true;
+                    continue;
+                }
+            }
+            let elapsed = listener_cloned.last_seen.lock().unwrap().elapsed();
+            if elapsed >= ping_timeout {
+                // The peer is unresponsive, so skip the close handshake.
+                println!("client did not respond to ping in {elapsed:?}");
+                cloned
+                    .metrics
+                    .ws_heartbeat_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+
+            let idle = elapsed >= ping_interval;
+            if idle != listener_cloned.idle.swap(idle, Ordering::Relaxed) {
+                if idle {
+                    cloned.metrics.ws_idle.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    cloned.metrics.ws_idle.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+
+            if listener_cloned
+                .control
+                .send(Message::Ping(Vec::new()))
+                .await
+                .is_err()
+            {
                 break;
             }
         }
     });
 
     // Listen for key changes, and then send them to the client via send_task.
+    let listener_cloned = listener_context.clone();
+    let cloned = context.clone();
     let mut listen_key_task = tokio::spawn(async move {
-        let sender = listener_context.sender.clone();
-        let mut receiver = context.broadcast.subscribe();
-        while let Ok(i) = receiver.recv().await {
-            let (key, value) = i;
-            if listener_context.listening.read().unwrap().contains(&key) {
-                sender
-                    .send(ServerMessage::Data { key, value })
-                    .await
-                    .unwrap();
+        let listener_context = listener_cloned;
+        let context = cloned;
+        loop {
+            match broadcast_receiver.recv().await {
+                Ok(Event::Publish(publication)) => {
+                    let subscribed = listener_context
+                        .channels
+                        .lock()
+                        .unwrap()
+                        .contains(&publication.channel);
+                    // Every publication is delivered in order, they are not coalesced like key updates.
+                    if subscribed {
+                        listener_context.send(ServerMessage::Publish {
+                            channel: publication.channel,
+                            value: publication.value,
+                        });
+                    }
+                }
+                Ok(Event::Change(change)) => {
+                    let mut listening = listener_context.listening.lock().await;
+                    if let Some(last_seq) = listening.get_mut(&change.key) {
+                        // Skip changes already covered by the snapshot or the replay.
+                        if change.seq > *last_seq {
+                            *last_seq = change.seq;
+                            listener_context.send_change(change);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    // Missed changes can't be recovered from the channel, resend the current state instead.
+                    println!("client lagged behind by {skipped} changes");
+                    context
+                        .metrics
+                        .broadcast_lagged
+                        .fetch_add(1, Ordering::Relaxed);
+                    context
+                        .metrics
+                        .broadcast_lagged_changes
+                        .fetch_add(skipped, Ordering::Relaxed);
+
+                    let mut listening = listener_context.listening.lock().await;
+                    listener_context.send(ServerMessage::Lagged { skipped });
+                    let keys: Vec<String> = listening.keys().cloned().collect();
+                    for key in keys {
+                        send_snapshot(&listener_context, &context, &mut listening, key).await;
+                    }
+                }
+                Err(RecvError::Closed) => break,
             }
         }
     });
 
     tokio::select! {
-        _ = &mut recv_task => {
-            send_task.abort();
-            listen_key_task.abort();
-        },
-        _ = &mut send_task => {
-            recv_task.abort();
-            listen_key_task.abort();
-        },
-        _ = &mut listen_key_task => {
-            recv_task.abort();
-            send_task.abort();
-        }
+        _ = &mut recv_task => {},
+        _ = &mut send_task => {},
+        _ = &mut listen_key_task => {},
+        _ = &mut heartbeat_task => {},
+        _ = session.kicked.notified() => {},
+    }
+    recv_task.abort();
+    send_task.abort();
+    listen_key_task.abort();
+    heartbeat_task.abort();
+    if let Some(presence_task) = listener_context.presence_task.lock().unwrap().take() {
+        presence_task.abort();
     }
 
+    context.sessions.unregister(session.id);
+    context.metrics.ws_connected.fetch_sub(1, Ordering::Relaxed);
+    if listener_context.idle.load(Ordering::Relaxed) {
+        context.metrics.ws_idle.fetch_sub(1, Ordering::Relaxed);
+    }
     println!("client disconnected");
 }
 
@@ -103,97 +522,414 @@ async fn process_message(
     app_context: &Arc<AppContext>,
     msg: Message,
 ) -> ControlFlow<(), ()> {
-    match msg {
-        Message::Text(t) => {
-            // parse message and if message is not valid, continue
-            let msg: Result<ClientMessage, serde_json::Error> = serde_json::from_str(&t);
-            if let Err(err) = msg {
-                // unable to parse message
-                context.sender.send(ServerMessage::Error {
-                    message: err.to_string(),
-                }).await.unwrap();
+    // parse message and if message is not valid, continue
+    let msg: Result<ClientMessage, CodecError> = match &msg {
+        // Text frames are always JSON, binary frames use the negotiated encoding.
+        Message::Text(t) => Encoding::Json.decode(t.as_bytes()),
+        Message::Binary(b) => {
+            let encoding = *context.encoding.read().unwrap();
+            encoding.decode(b)
+        }
+        Message::Close(_) => {
+            return ControlFlow::Break(());
+        }
+        _ => return ControlFlow::Continue(()),
+    };
+    if let Err(err) = msg {
+        // unable to parse message
+        context.send(ServerMessage::Error {
+            message: err.to_string(),
+            id: None,
+        });
+        return ControlFlow::Continue(());
+    }
+    println!("client sent: {:?}", msg);
+
+    let msg = msg.unwrap();
+    let ip = context.session.client_ip;
+    let name = context
+        .session
+        .secret
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|secret| secret.name.clone());
+    if app_context
+        .rate_limits
+        .acquire(name.as_deref(), ip)
+        .is_err()
+    {
+        context.send(ServerMessage::Error {
+            message: "rate limited".to_owned(),
+            id: msg.id(),
+        });
+        return ControlFlow::Continue(());
+    }
+    // check if the client is authorized
+    if !context.is_authorized() {
+        if let ClientMessage::Authenticate(request) = msg {
+            if app_context.lockouts.check(ip).is_some() {
+                context.close(CLOSE_LOCKED_OUT, "too many failed attempts");
                 return ControlFlow::Continue(());
             }
-
-            // check if the client is authorized
-            let is_authorized = *context.authorized.read().unwrap();
-            if !is_authorized {
-                let msg = msg.unwrap();
-                match msg {
-                    ClientMessage::Authenticate(secret) => {
-                        if app_context.secrets.read().await.contains_key(&secret) {
-                            *context.authorized.write().unwrap() = true;
-                            context.sender.send(ServerMessage::Authenticated).await.unwrap();
-                            println!("client authorized");
-                        } else {
-                            println!("client unauthorized");
-                        }
-                    }
-                    _ => {
-                        println!("client unauthorized");
-                    }
+            let (secret, encoding) = request.into_parts();
+            // Hold the secrets while authenticating, so a reload revoking the secret can't be missed.
+            let secrets = app_context.secrets.read().await;
+            if let Some(secret) = authenticate(&secrets, &app_context.token_signer, &secret)
+                .and_then(|secret| secret.allowed_from(ip))
+            {
+                let max = app_context.config.ws_max_connections_per_secret;
+                let accepted = app_context
+                    .sessions
+                    .authenticate(&context.session, secret, max);
+                drop(secrets);
+                if !accepted {
+                    reject_too_many_connections(context, app_context);
+                    return ControlFlow::Continue(());
                 }
-                return ControlFlow::Continue(());
+                app_context.sessions.notify_changed();
+                if let Some(encoding) = encoding {
+                    // Replies from here on, including `Authenticated`, use the new encoding.
+                    *context.encoding.write().unwrap() = encoding;
+                }
+                on_authenticated(context, app_context).await;
+                println!("client authorized");
             } else {
-                match msg.unwrap() {
-                    ClientMessage::Subscribe(keys) => {
-                        // push keys to listening
-                        {
-                            let mut listening = context.listening.write().unwrap();
-                            for key in &keys {
-                                if !listening.contains(key) {
-                                    listening.push(key.clone());
-                                }
-                            }
-                        }
-                        // return the keys and their values
-                        for key in keys {
-                            let value = app_context.key_service.get_key(&key).await;
-                            if let Err(err) = value {
-                                context.sender.send(ServerMessage::Error {
-                                    message: err.to_string(),
-                                }).await.unwrap();
-                            } else {
-                                context.sender.send(ServerMessage::Subscribed {
-                                    key: key.clone(),
-                                    value: value.unwrap(),
-                                }).await.unwrap();
-                            }
-                        }
-                    }
-                    ClientMessage::Data { key, value } => {
-                        if context.listening.read().unwrap().contains(&key) {
-                            let req = app_context.key_service.put_key(&key, value).await;
-                            if let Err(err) = req {
-                                context.sender.send(ServerMessage::Error {
-                                    message: err.to_string(),
-                                }).await.unwrap();
-                            }
-                        }
-                    }
-                    ClientMessage::Patch { key, value } => {
-                        if context.listening.read().unwrap().contains(&key) {
-                            let req = app_context.key_service.patch_key(&key, value).await;
-                            if let Err(err) = req {
-                                context.sender.send(ServerMessage::Error {
-                                    message: err.to_string(),
-                                }).await.unwrap();
-                            }
-                        }
+                drop(secrets);
+                println!("client unauthorized");
+                let failures = context.auth_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if app_context.lockouts.record_failure(ip).is_some() {
+                    context.close(CLOSE_LOCKED_OUT, "too many failed attempts");
+                } else if failures >= app_context.config.ws_max_auth_failures {
+                    context.close(CLOSE_UNAUTHORIZED, "invalid secret");
+                } else {
+                    context.send(ServerMessage::Error {
+                        message: "invalid secret".to_owned(),
+                        id: None,
+                    });
+                }
+            }
+            return ControlFlow::Continue(());
+        }
+        // Without public keys, nothing else is allowed before authenticating.
+        if context.anonymous.is_none() {
+            println!("client unauthorized");
+            context.send(ServerMessage::Error {
+                message: "not authenticated".to_owned(),
+                id: None,
+            });
+            return ControlFlow::Continue(());
+        }
+    }
+    if !permits(context, &msg) {
+        context.send(ServerMessage::Error {
+            message: "forbidden".to_owned(),
+            id: msg.id(),
+        });
+        return ControlFlow::Continue(());
+    }
+    match msg {
+        ClientMessage::Subscribe(request) => {
+            subscribe(context, app_context, request.into_options()).await;
+        }
+        ClientMessage::Data { id, key, value } => {
+            let req = app_context
+                .key_service
+                .put_key(&key, value, &context.author())
+                .await;
+            reply_written(context, id, key, req);
+        }
+        ClientMessage::Patch { id, key, value } => {
+            let req = app_context
+                .key_service
+                .patch_key(&key, value, &context.author())
+                .await;
+            reply_written(context, id, key, req);
+        }
+        ClientMessage::Delete { id, key } => {
+            let req = app_context
+                .key_service
+                .delete_key(&key, &context.author())
+                .await;
+            reply_written(context, id, key, req);
+        }
+        ClientMessage::Get { id, key } => {
+            let reply = match app_context.key_service.get_entry(&key).await {
+                Ok(entry) => ServerMessage::Value {
+                    id,
+                    key,
+                    value: entry.value,
+                    seq: entry.revision,
+                },
+                Err(err) => ServerMessage::Error {
+                    message: err.to_string(),
+                    id,
+                },
+            };
+            context.send(reply);
+        }
+        ClientMessage::List { id } => {
+            let reply = if !app_context.config.enable_list {
+                ServerMessage::Error {
+                    message: "list is disabled".to_owned(),
+                    id,
+                }
+            } else {
+                match app_context.key_service.list_keys().await {
+                    Ok(mut keys) => {
+                        keys.retain(|key| context.allows(Permission::Read, key));
+                        ServerMessage::Keys { id, keys }
                     }
-                    _ => {}
+                    Err(err) => ServerMessage::Error {
+                        message: err.to_string(),
+                        id,
+                    },
                 }
+            };
+            context.send(reply);
+        }
+        ClientMessage::SubscribeChannels(channels) => {
+            context.channels.lock().unwrap().extend(channels);
+        }
+        ClientMessage::Publish { channel, value } => {
+            if let Err(err) = app_context.key_service.publish(&channel, value).await {
+                context.send(ServerMessage::Error {
+                    message: err.to_string(),
+                    id: None,
+                });
             }
-            println!("client sent: {}", t);
         }
-        Message::Close(_) => {
-            return ControlFlow::Break(());
+        ClientMessage::Presence(enabled) => {
+            set_presence(context, app_context, enabled);
         }
-        _ => {}
+        ClientMessage::Authenticate(_) => {}
     }
     ControlFlow::Continue(())
 }
 
+/// Whether the secret allows the message.
+/// Subscriptions are checked by `subscribe`, which also handles the keys given at upgrade time.
+fn permits(context: &ListenerContext, msg: &ClientMessage) -> bool {
+    match msg {
+        ClientMessage::Authenticate(_) | ClientMessage::Subscribe(_) => true,
+        ClientMessage::Data { key, .. } | ClientMessage::Patch { key, .. } => {
+            context.allows(Permission::Write, key)
+        }
+        ClientMessage::Delete { key, .. } => context.allows(Permission::Delete, key),
+        ClientMessage::Get { key, .. } => context.allows(Permission::Read, key),
+        // The keys out of the scopes are left out.
+        ClientMessage::List { .. } => context.has_permission(Permission::Read),
+        ClientMessage::SubscribeChannels(channels) => channels
+            .iter()
+            .all(|channel| context.allows(Permission::Read, channel)),
+        ClientMessage::Publish { channel, .. } => context.allows(Permission::Write, channel),
+        ClientMessage::Presence(_) => context.has_permission(Permission::Admin),
+    }
+}
+
+/// Confirm the authentication, then subscribe to the keys given at upgrade time.
+async fn on_authenticated(context: &Arc<ListenerContext>, app_context: &Arc<AppContext>) {
+    context.send(ServerMessage::Authenticated);
+    subscribe_pending(context, app_context).await;
+}
+
+/// Subscribe to the keys given at upgrade time.
+async fn subscribe_pending(context: &Arc<ListenerContext>, app_context: &Arc<AppContext>) {
+    let keys = std::mem::take(&mut *context.pending_keys.lock().unwrap());
+    if !keys.is_empty() {
+        let options = SubscribeOptions {
+            keys,
+            since: None,
+            max_rate: None,
+        };
+        subscribe(context, app_context, options).await;
+    }
+}
+
+/// Reply to a write with `Written`, or `Error` if it failed.
+/// Without an `id`, only errors are sent.
+fn reply_written(
+    context: &ListenerContext,
+    id: Option<RequestId>,
+    key: String,
+    req: Result<u64, KeyServiceError>,
+) {
+    match req {
+        Ok(seq) => {
+            if id.is_some() {
+                context.send(ServerMessage::Written { id, key, seq });
+            }
+        }
+        Err(err) => {
+            context.send(ServerMessage::Error {
+                message: err.to_string(),
+                id,
+            });
+        }
+    }
+}
+
+fn change_message(change: Change) -> ServerMessage {
+    match change.value {
+        Some(value) => ServerMessage::Data {
+            key: change.key,
+            value,
+            seq: change.seq,
+        },
+        None => ServerMessage::Deleted {
+            key: change.key,
+            seq: change.seq,
+        },
+    }
+}
+
+/// Add the keys to the listening list and send their state to the client.
+///
+/// With `since`, only the changes after that `seq` are replayed from the change log,
+/// followed by `Resumed`. If the log no longer covers `since`, or without it,
+/// the current values are sent as `Subscribed` snapshots.
+/// With `max_rate`, updates of the keys are sent at most that many times per second.
+async fn subscribe(
+    context: &Arc<ListenerContext>,
+    app_context: &Arc<AppContext>,
+    options: SubscribeOptions,
+) {
+    let SubscribeOptions {
+        keys,
+        since,
+        max_rate,
+    } = options;
+    let interval = match max_rate {
+        Some(rate) if !(rate >= MIN_RATE && rate.is_finite()) => {
+            context.send(ServerMessage::Error {
+                message: format!("max_rate must be a number of at least {MIN_RATE}"),
+                id: None,
+            });
+            return;
+        }
+        Some(rate) => Some(Duration::from_secs_f64(1.0 / rate)),
+        None => None,
+    };
+    let forbidden: Vec<&str> = keys
+        .iter()
+        .filter(|key| !context.allows(Permission::Read, key))
+        .map(String::as_str)
+        .collect();
+    if !forbidden.is_empty() {
+        context.send(ServerMessage::Error {
+            message: format!("forbidden: {}", forbidden.join(", ")),
+            id: None,
+        });
+        return;
+    }
+    for key in &keys {
+        context
+            .outbox
+            .throttle(OutboxKey::Data(key.clone()), interval);
+    }
+    context
+        .session
+        .subscriptions
+        .lock()
+        .unwrap()
+        .extend(keys.iter().cloned());
+    app_context.sessions.notify_changed();
+
+    // Hold the listening lock while reading, so listen_key_task can't drop or duplicate
+    // a change committed between the read and the insertion.
+    let mut listening = context.listening.lock().await;
+
+    if let Some(since) = since {
+        let changes = app_context.changes.read().await;
+        if let Some(missed) = changes.since(since) {
+            for key in &keys {
+                let last_seq = listening.entry(key.clone()).or_insert(since);
+                *last_seq = (*last_seq).max(since);
+            }
+            for change in missed {
+                if let Some(last_seq) = listening.get_mut(&change.key) {
+                    if change.seq > *last_seq && keys.contains(&change.key) {
+                        *last_seq = change.seq;
+                        context.send_change(change);
+                    }
+                }
+            }
+            context.send(ServerMessage::Resumed {
+                keys,
+                seq: changes.latest_seq(),
+            });
+            return;
+        }
+    }
+
+    // return the keys and their values
+    for key in keys {
+        send_snapshot(context, app_context, &mut listening, key).await;
+    }
+}
+
+/// Start or stop sending the connected sessions as `Presence` whenever they change.
+fn set_presence(context: &Arc<ListenerContext>, app_context: &Arc<AppContext>, enabled: bool) {
+    let mut presence_task = context.presence_task.lock().unwrap();
+    if let Some(task) = presence_task.take() {
+        task.abort();
+    }
+    if !enabled {
+        return;
+    }
+
+    let context = context.clone();
+    let app_context = app_context.clone();
+    *presence_task = Some(tokio::spawn(async move {
+        let mut changed = app_context.sessions.subscribe();
+        loop {
+            let clients = app_context.sessions.list();
+            context.send_latest(OutboxKey::Presence, ServerMessage::Presence { clients });
+            if changed.changed().await.is_err() {
+                break;
+            }
+        }
+    }));
+}
+
+/// Send the current value of the key as `Subscribed`, and mark it as the last sent change.
+/// The caller holds the listening lock.
+async fn send_snapshot(
+    context: &ListenerContext,
+    app_context: &Arc<AppContext>,
+    listening: &mut HashMap<String, u64>,
+    key: String,
+) {
+    match app_context.key_service.get_entry(&key).await {
+        Ok(entry) => {
+            let last_seq = listening.entry(key.clone()).or_insert(0);
+            *last_seq = (*last_seq).max(entry.revision);
+            context.send_latest(
+                OutboxKey::Data(key.clone()),
+                ServerMessage::Subscribed {
+                    key: key.clone(),
+                    value: entry.value,
+                    seq: entry.revision,
+                },
+            );
+        }
+        Err(err) => {
+            context.send(ServerMessage::Error {
+                message: err.to_string(),
+                id: None,
+            });
+        }
+    }
+}
+
+/// The key under which the outbox coalesces messages.
+/// Presence has its own, so it can't collide with any data key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum OutboxKey {
+    Data(String),
+    Presence,
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 enum ServerMessage {
@@ -202,27 +938,196 @@ enum ServerMessage {
     Subscribed {
         key: String,
         value: serde_json::Value,
+        seq: u64,
     },
     Data {
         key: String,
         value: serde_json::Value,
+        seq: u64,
+    },
+    /// A subscribed key was deleted.
+    Deleted {
+        key: String,
+        seq: u64,
+    },
+    /// Every missed change since the requested `seq` has been replayed.
+    Resumed {
+        keys: Vec<String>,
+        seq: u64,
+    },
+    /// A message published to a subscribed ephemeral channel.
+    Publish {
+        channel: String,
+        value: serde_json::Value,
+    },
+    /// The connected sessions, sent while subscribed to presence.
+    Presence {
+        clients: Vec<SessionInfo>,
+    },
+    /// The client fell behind and `skipped` changes were dropped.
+    /// Followed by `Subscribed` snapshots of every subscribed key.
+    Lagged {
+        skipped: u64,
+    },
+    /// The reply to `Get`.
+    Value {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        key: String,
+        value: serde_json::Value,
+        seq: u64,
+    },
+    /// The reply to `List`.
+    Keys {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        keys: Vec<String>,
+    },
+    /// The reply to `Data`, `Patch` and `Delete` with an `id`.
+    Written {
+        id: Option<RequestId>,
+        key: String,
+        seq: u64,
     },
     Error {
         message: String, // invalid-message or so...
+        /// The `id` of the request which failed, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
     },
 }
 
+/// Correlates a reply with its request. Any JSON value chosen by the client.
+type RequestId = serde_json::Value;
+
+impl Coalesce for ServerMessage {
+    /// A newer `Data` keeps a pending `Subscribed`, so the client still sees its subscription confirmed.
+    fn coalesce(self, newer: Self) -> Self {
+        match (self, newer) {
+            (ServerMessage::Subscribed { .. }, ServerMessage::Data { key, value, seq }) => {
+                ServerMessage::Subscribed { key, value, seq }
+            }
+            (_, newer) => newer,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 enum ClientMessage {
-    Authenticate(String),
-    Subscribe(Vec<String>),
+    Authenticate(AuthenticateRequest),
+    Subscribe(SubscribeRequest),
     Data {
+        #[serde(default)]
+        id: Option<RequestId>,
         key: String,
         value: serde_json::Value,
     },
     Patch {
+        #[serde(default)]
+        id: Option<RequestId>,
+        key: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        #[serde(default)]
+        id: Option<RequestId>,
+        key: String,
+    },
+    /// Get a value once, without subscribing.
+    Get {
+        #[serde(default)]
+        id: Option<RequestId>,
         key: String,
+    },
+    List {
+        #[serde(default)]
+        id: Option<RequestId>,
+    },
+    /// Subscribe to ephemeral channels.
+    SubscribeChannels(Vec<String>),
+    /// Publish a value to an ephemeral channel, without storing it.
+    Publish {
+        channel: String,
         value: serde_json::Value,
     },
+    /// Subscribe to, or unsubscribe from, the connected sessions.
+    Presence(bool),
+}
+
+impl ClientMessage {
+    /// The id of the request, echoed in its reply.
+    fn id(&self) -> Option<RequestId> {
+        match self {
+            ClientMessage::Data { id, .. }
+            | ClientMessage::Patch { id, .. }
+            | ClientMessage::Delete { id, .. }
+            | ClientMessage::Get { id, .. }
+            | ClientMessage::List { id } => id.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Either a plain secret, or `{ "secret": secret, "encoding": "msgpack" }` to switch the encoding.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuthenticateRequest {
+    Secret(String),
+    Options {
+        secret: String,
+        encoding: Option<Encoding>,
+    },
+}
+
+impl std::fmt::Debug for AuthenticateRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthenticateRequest::Secret(_) => f.write_str("Secret([redacted])"),
+            AuthenticateRequest::Options { encoding, .. } => f
+                .debug_struct("Options")
+                .field("secret", &"[redacted]")
+                .field("encoding", encoding)
+                .finish(),
+        }
+    }
+}
+
+impl AuthenticateRequest {
+    fn into_parts(self) -> (String, Option<Encoding>) {
+        match self {
+            AuthenticateRequest::Secret(secret) => (secret, None),
+            AuthenticateRequest::Options { secret, encoding } => (secret, encoding),
+        }
+    }
+}
+
+/// Either a plain list of keys, or `{ "keys": [...], "since": seq, "max_rate": per_second }`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SubscribeRequest {
+    Keys(Vec<String>),
+    Options(SubscribeOptions),
+}
+
+#[derive(Deserialize, Debug)]
+struct SubscribeOptions {
+    keys: Vec<String>,
+    /// Resume after this `seq`.
+    since: Option<u64>,
+    /// The maximum number of updates per second for each key.
+    max_rate: Option<f64>,
+}
+
+impl SubscribeRequest {
+    fn into_options(self) -> SubscribeOptions {
+        match self {
+            SubscribeRequest::Keys(keys) => SubscribeOptions {
+                keys,
+                since: None,
+                max_rate: None,
+            },
+            SubscribeRequest::Options(options) => options,
+        }
+    }
 }
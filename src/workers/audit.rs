@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::changes::Operation;
+
+/// A write made by a secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Unix time in milliseconds.
+    pub time: u64,
+    /// The name of the secret.
+    pub name: String,
+    pub key: String,
+    pub operation: Operation,
+    pub remote_addr: Option<String>,
+    /// The revision of the key before the write. `None` if it didn't exist.
+    pub before_revision: Option<u64>,
+    pub after_revision: u64,
+}
+
+/// This appends every audit record to the log file as a JSON line.
+/// Once the file exceeds `max_size` bytes, it's rotated to `[path].1`, keeping up to `max_files` old files.
+pub async fn audit_worker(
+    mut records: mpsc::Receiver<AuditRecord>,
+    path: String,
+    max_size: u64,
+    max_files: usize,
+) {
+    let path = PathBuf::from(path);
+    while let Some(record) = records.recv().await {
+        if let Err(e) = append_record(&path, &record, max_size, max_files) {
+            println!("failed to write the audit log: {}", e);
+        }
+    }
+}
+
+fn append_record(
+    path: &Path,
+    record: &AuditRecord,
+    max_size: u64,
+    max_files: usize,
+) -> std::io::Result<()> {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size >= max_size {
+        rotate(path, max_files)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Shift `[path].n` to `[path].n+1`, dropping the oldest, then move `path` to `[path].1`.
+fn rotate(path: &Path, max_files: usize) -> std::io::Result<()> {
+    if max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    let _ = std::fs::remove_file(rotated_path(path, max_files));
+    for n in (1..max_files).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            std::fs::rename(from, rotated_path(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Read the latest `limit` audit records kept on disk matching the filter, oldest first.
+/// The files are read line by line, so only the records returned are held in memory.
+/// Files last written before `since` (unix time in milliseconds) are skipped.
+/// Lines that fail to parse are skipped.
+pub fn read_audit_log(
+    path: &str,
+    max_files: usize,
+    since: Option<u64>,
+    limit: usize,
+    filter: impl Fn(&AuditRecord) -> bool,
+) -> std::io::Result<Vec<AuditRecord>> {
+    let path = Path::new(path);
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let mut records = VecDeque::with_capacity(limit.min(1024));
+    let files = (1..=max_files)
+        .rev()
+        .map(|n| rotated_path(path, n))
+        .chain(std::iter::once(path.to_path_buf()));
+    for file_path in files {
+        let file = match std::fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let modified = file.metadata()?.modified()?;
+        let modified = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        if since.is_some_and(|since| modified < since) {
+            continue;
+        }
+        for line in std::io::BufReader::new(file).lines() {
+            let Ok(record) = serde_json::from_str(&line?) else {
+                continue;
+            };
+            if !filter(&record) {
+                continue;
+            }
+            if records.len() == limit {
+                records.pop_front();
+            }
+            records.push_back(record);
+        }
+    }
+    Ok(records.into())
+}
@@ -1,13 +1,22 @@
-use tokio::sync::{broadcast, mpsc::Receiver};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc::Receiver, RwLock};
+
+use crate::changes::{ChangeLog, Event};
 
 /// Broadcaster worker
-/// This worker broadcasts the changed data.
+/// This worker records the changed data to the replay buffer, then broadcasts it.
+/// Publications to ephemeral channels are only broadcasted.
 pub async fn worker_broadcaster(
-    mut rx: Receiver<(String, serde_json::Value)>,
-    tx: broadcast::Sender<(String, serde_json::Value)>,
+    mut rx: Receiver<Event>,
+    tx: broadcast::Sender<Event>,
+    changes: Arc<RwLock<ChangeLog>>,
 ) {
     loop {
         let data = rx.recv().await.unwrap();
+        if let Event::Change(change) = &data {
+            // Record before broadcasting, so a resuming client never misses a change in between.
+            changes.write().await.push(change.clone());
+        }
         tx.send(data).unwrap();
     }
 }
@@ -5,6 +5,8 @@ use notify::{
 use std::path::Path;
 use tokio;
 use tokio::sync::mpsc::{self, channel, Receiver};
+
+use crate::workers::file_save::{SEQ_FILE_NAME, SEQ_TEMP_FILE_NAME};
 // FIXME: Those are prototyped-required to fixed.
 
 /// Listen files worker
@@ -19,6 +21,11 @@ pub async fn file_listen_worker(path: &str, tx: mpsc::Sender<String>) {
         let res = rx.recv().await;
         match res {
             Some(event) => match event {
+                // The sequence number isn't a key.
+                Ok(event)
+                    if event.paths.last().is_some_and(|path| {
+                        path.ends_with(SEQ_FILE_NAME) || path.ends_with(SEQ_TEMP_FILE_NAME)
+                    }) => {}
                 Ok(event) => match event.kind {
                     /*
                     EventKind::Create(kind) => {
@@ -30,25 +37,20 @@ pub async fn file_listen_worker(path: &str, tx: mpsc::Sender<String>) {
 
                         }
                     }*/
-                    EventKind::Modify(kind) => match kind {
-                            ModifyKind::Data(_) | ModifyKind::Any /* Windows OS somehow return this as any.  */=> {
-                                let path = event.paths.last().unwrap();
-                                // parse path and extract "file" from "/./data/file.json"
-                                let path = parse_path(path);
-                                println!("file modified: {:?}", path);
-                                tx.send(path).await.unwrap();
-                            }
-                            _ => {}
-                        },
-                    EventKind::Remove(kind) => {
-                        // some ide's using interesting mechanism to remove files so it wouldn't be detected or so.
-                        if kind == RemoveKind::File {
-                            let path = event.paths.last().unwrap();
-                            // parse path and extract "file" from "/./data/file.json"
-                            let path = parse_path(path);
-                            println!("file removed: {:?}", path);
-                            tx.send(path).await.unwrap();
-                        }
+                    EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any /* Windows OS somehow return this as any.  */) => {
+                        let path = event.paths.last().unwrap();
+                        // parse path and extract "file" from "/./data/file.json"
+                        let path = parse_path(path);
+                        println!("file modified: {:?}", path);
+                        tx.send(path).await.unwrap();
+                    }
+                    // some ide's using interesting mechanism to remove files so it wouldn't be detected or so.
+                    EventKind::Remove(RemoveKind::File) => {
+                        let path = event.paths.last().unwrap();
+                        // parse path and extract "file" from "/./data/file.json"
+                        let path = parse_path(path);
+                        println!("file removed: {:?}", path);
+                        tx.send(path).await.unwrap();
                     }
 
                     _ => {}
@@ -64,7 +66,7 @@ pub async fn file_listen_worker(path: &str, tx: mpsc::Sender<String>) {
     }
 }
 
-fn async_watcher() -> Result<(RecommendedWatcher, Receiver<Result<Event>>)> {
+pub fn async_watcher() -> Result<(RecommendedWatcher, Receiver<Result<Event>>)> {
     let (tx, rx) = channel(16);
 
     let watcher = RecommendedWatcher::new(
@@ -95,7 +97,6 @@ fn parse_path(path: &Path) -> String {
         .unwrap()
         .to_str()
         .unwrap()
-        .to_string()
         .split(".json")
         .next()
         .unwrap()
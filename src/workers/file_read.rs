@@ -2,7 +2,7 @@ use std::{io::Read, path::Path, sync::Arc};
 
 use tokio::sync::mpsc::Receiver;
 
-use crate::service::{KeyService, KeyServiceTrait};
+use crate::service::{Author, KeyService, KeyServiceTrait};
 /// File read worker
 /// This worker reads from file and compares the data, then modify if is modified.
 pub async fn file_read_worker(
@@ -11,6 +11,7 @@ pub async fn file_read_worker(
     key_service: Arc<KeyService>,
 ) {
     let path = Path::new(data_dir_path);
+    let author = Author::file();
     loop {
         let key = rx.recv().await.unwrap();
 
@@ -43,12 +44,12 @@ pub async fn file_read_worker(
         if text.is_empty() {
             if is_key_exists {
                 key_service
-                    .put_key(&key, serde_json::Value::Null)
+                    .put_key(&key, serde_json::Value::Null, &author)
                     .await
                     .unwrap();
             } else {
                 key_service
-                    .post_key(&key, serde_json::Value::Null)
+                    .post_key(&key, serde_json::Value::Null, &author)
                     .await
                     .unwrap();
             }
@@ -77,10 +78,10 @@ pub async fn file_read_worker(
             };
 
             if value != parsed {
-                key_service.put_key(&key, parsed).await.unwrap();
+                key_service.put_key(&key, parsed, &author).await.unwrap();
             }
         } else {
-            key_service.post_key(&key, parsed).await.unwrap();
+            key_service.post_key(&key, parsed, &author).await.unwrap();
         }
     }
 }
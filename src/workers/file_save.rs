@@ -1,16 +1,25 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::mpsc;
+
+/// The file in the data directory keeping the latest sequence number across restarts.
+pub const SEQ_FILE_NAME: &str = ".seq";
+/// Written first and renamed to `SEQ_FILE_NAME`, so a crash can't leave a truncated one.
+pub const SEQ_TEMP_FILE_NAME: &str = ".seq.tmp";
+
 /// This collects data events about each modified piece of data as it comes in, and stores the latest data every n seconds.
 ///
 /// # Arguments
 ///
-/// * `data_events` - The receiver of data events.
+/// * `data_events` - The receiver of data events. `None` deletes the key.
 /// * `data_dir_path` - The path to the data directory.
 /// * `save_interval` - The interval to save the data to disk. (in milliseconds)
+/// * `seq` - The sequence number of the latest change, saved along the data.
 pub async fn save_data_worker(
-    mut data_events: mpsc::Receiver<(String, serde_json::Value)>, // K, V.
+    mut data_events: mpsc::Receiver<(String, Option<serde_json::Value>)>, // K, V.
     data_dir_path: String,
     save_interval: u64,
+    seq: &AtomicU64,
 ) {
     let mut data = HashMap::new();
     loop {
@@ -24,6 +33,12 @@ pub async fn save_data_worker(
                 if let Err(e) = save_data_to_disk(&data, &data_dir_path).await {
                     panic!("failed to save data to disk: {}", e);
                 }
+                // Saved after the data, so it's never behind the changes on disk.
+                if !data.is_empty() {
+                    if let Err(e) = save_seq(&data_dir_path, seq.load(Ordering::SeqCst)) {
+                        panic!("failed to save the sequence number: {}", e);
+                    }
+                }
 
                 data.clear();
             }
@@ -34,9 +49,9 @@ pub async fn save_data_worker(
 /// Save the given data to the given path.
 /// If the file does not exist, create a sample and save it to the given path.
 /// Single key is just `[key].json`
-/// If the file exists, overwrite it.
+/// If the file exists, overwrite it. If the value is `None`, remove it.
 async fn save_data_to_disk(
-    data: &HashMap<String, serde_json::Value>,
+    data: &HashMap<String, Option<serde_json::Value>>,
     data_dir_path: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // data dir should be exist at this moment.
@@ -48,13 +63,44 @@ async fn save_data_to_disk(
     for (key, value) in data {
         let file_path = data_dir_path.join(format!("{}.json", key));
         let file_path = file_path.to_str().unwrap();
-        let file = std::fs::File::create(file_path)?;
-        serde_json::to_writer_pretty(file, value)?;
+        match value {
+            Some(value) => {
+                let file = std::fs::File::create(file_path)?;
+                serde_json::to_writer_pretty(file, value)?;
+            }
+            None => {
+                if let Err(e) = std::fs::remove_file(file_path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+fn save_seq(data_dir_path: &str, seq: u64) -> std::io::Result<()> {
+    let data_dir_path = std::path::Path::new(data_dir_path);
+    let temp_path = data_dir_path.join(SEQ_TEMP_FILE_NAME);
+    std::fs::write(&temp_path, seq.to_string())?;
+    std::fs::rename(temp_path, data_dir_path.join(SEQ_FILE_NAME))
+}
+
+/// Load the sequence number saved by `save_data_worker`, `0` if there's none yet.
+pub fn load_seq(data_dir_path: &str) -> u64 {
+    let seq_file_path = std::path::Path::new(data_dir_path).join(SEQ_FILE_NAME);
+    match std::fs::read_to_string(&seq_file_path) {
+        Ok(seq) => seq
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid sequence number in {:?}", seq_file_path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => panic!("failed to read {:?}: {}", seq_file_path, e),
+    }
+}
+
 pub async fn load_data_from_disk(
     data_dir_path: &str,
 ) -> Result<HashMap<String, serde_json::Value>, Box<dyn std::error::Error>> {
@@ -89,7 +135,7 @@ pub async fn load_data_from_disk(
             serde_json::Value::Null
         } else {
             serde_json::from_reader(file).unwrap_or_else(|_| {
-                format!("failed to parse file: {}", file_path_str);
+                println!("failed to parse file: {}", file_path_str);
                 serde_json::Value::Null
             })
         };
@@ -1,4 +1,7 @@
+pub mod audit;
 pub mod broadcaster;
 pub mod file_listen;
 pub mod file_read;
 pub mod file_save;
+pub mod secret_reload;
+pub mod tls_reload;
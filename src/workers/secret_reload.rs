@@ -0,0 +1,81 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use crate::config;
+use crate::context::AppContext;
+use crate::workers::file_listen::async_watcher;
+
+/// Secret reload worker
+/// This worker reloads the secret file whenever it changes, or on SIGHUP.
+pub async fn secret_reload_worker(path: String, context: Arc<AppContext>) {
+    tokio::join!(watch_file(&path, &context), watch_hangup(&path, &context));
+}
+
+async fn watch_file(path: &str, context: &AppContext) {
+    let file = Path::new(path);
+    let file_name = file.file_name().unwrap().to_owned();
+    // Watch the directory, since editors often replace the file instead of writing to it.
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let (mut watcher, mut rx) = async_watcher().unwrap();
+    watcher.watch(dir, RecursiveMode::NonRecursive).unwrap();
+
+    while let Some(event) = rx.recv().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                println!("watch error: {:?}", e);
+                continue;
+            }
+        };
+        let is_secret_file = event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(file_name.as_os_str()));
+        if !is_secret_file || !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            continue;
+        }
+        // A save usually comes with several events, reload once they settle.
+        sleep(Duration::from_millis(100)).await;
+        while rx.try_recv().is_ok() {}
+        reload(path, context).await;
+    }
+}
+
+#[cfg(unix)]
+async fn watch_hangup(path: &str, context: &AppContext) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        println!("SIGHUP received, reloading the secrets.");
+        reload(path, context).await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn watch_hangup(_path: &str, _context: &AppContext) {}
+
+/// Swap in the secrets from the file, keeping the old ones if it's invalid.
+async fn reload(path: &str, context: &AppContext) {
+    let secrets = match config::read_secrets(path) {
+        Ok(secrets) => secrets,
+        Err(err) => {
+            println!("Unable to reload the secrets, keeping the old ones: {err}");
+            return;
+        }
+    };
+    // Hold the lock until the sessions are updated, so no session authenticates with a revoked secret.
+    let mut current = context.secrets.write().await;
+    context.sessions.apply_secrets(&secrets);
+    println!("Secrets reloaded: {} secrets.", secrets.secret.len());
+    *current = secrets;
+    context
+        .secrets_reloaded
+        .send_modify(|version| *version += 1);
+}
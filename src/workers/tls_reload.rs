@@ -0,0 +1,71 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use crate::config::Config;
+use crate::tls::{load_tls_config, SharedTlsConfig};
+use crate::workers::file_listen::async_watcher;
+
+/// TLS reload worker
+/// This worker reloads the certificate, its key and the client CA whenever one of them changes.
+/// New connections use the new certificate, open ones keep theirs.
+pub async fn tls_reload_worker(config: Config, tls_config: Option<SharedTlsConfig>) {
+    let Some(tls_config) = tls_config else {
+        // TLS is disabled, keep running along the other workers.
+        return std::future::pending().await;
+    };
+    let files: Vec<PathBuf> = [
+        &config.tls_cert_path,
+        &config.tls_key_path,
+        &config.tls_client_ca_path,
+    ]
+    .into_iter()
+    .flatten()
+    .map(PathBuf::from)
+    .collect();
+    // Watch the directories, since certificates are usually replaced rather than written to.
+    let dirs: HashSet<&Path> = files
+        .iter()
+        .map(|file| match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        })
+        .collect();
+
+    let (mut watcher, mut rx) = async_watcher().unwrap();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive).unwrap();
+    }
+
+    while let Some(event) = rx.recv().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                println!("watch error: {:?}", e);
+                continue;
+            }
+        };
+        let is_tls_file = event.paths.iter().any(|path| {
+            files
+                .iter()
+                .any(|file| path.file_name() == file.file_name())
+        });
+        if !is_tls_file || !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            continue;
+        }
+        // The certificate and the key are often replaced one after the other, reload once they settle.
+        sleep(Duration::from_millis(500)).await;
+        while rx.try_recv().is_ok() {}
+        match load_tls_config(&config) {
+            Ok(loaded) => {
+                *tls_config.write().unwrap() = Arc::new(loaded);
+                println!("TLS certificate reloaded.");
+            }
+            Err(err) => {
+                println!("Unable to reload the TLS certificate, keeping the old one: {err}")
+            }
+        }
+    }
+}